@@ -0,0 +1,44 @@
+// analysis.rs holds what an engine, built-in or UCI, has to say about a position
+
+use crate::protocol::MoveRecord;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Score { // from the point of view of the side to move
+    Centipawns(i32),
+    Mate(i32), // mate in that many moves, negative when the side to move is the one getting mated
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Analysis {
+    pub best: Option<MoveRecord>,
+    pub score: Option<Score>,
+    pub depth: u32, // how far the engine looked
+}
+
+impl Score {
+    /// The same score seen from white's side.
+    pub fn for_white(self, white_to_move: bool) -> Score {
+        if white_to_move {
+            return self;
+        }
+        match self {
+            Score::Centipawns(cp) => Score::Centipawns(-cp),
+            Score::Mate(moves) => Score::Mate(-moves),
+        }
+    }
+
+    /// How much of the evaluation bar is white, from 0 to 1. Half a pawn is worth a bit less than 60%.
+    pub fn bar_fraction(self) -> f32 {
+        match self {
+            Score::Centipawns(cp) => 1.0 / (1.0 + 10f32.powf(-cp as f32 / 400.0)),
+            Score::Mate(moves) => if moves > 0 { 1.0 } else { 0.0 },
+        }
+    }
+
+    pub fn label(self) -> String { // "+0.35", "-1.20", "#3", "#-2"
+        match self {
+            Score::Centipawns(cp) => format!("{:+.2}", cp as f32 / 100.0),
+            Score::Mate(moves) => format!("#{}", moves),
+        }
+    }
+}
@@ -0,0 +1,167 @@
+// mblomst-relay pairs players up by game name and passes their messages on, so only the relay needs an open port
+// run it with: cargo run --bin mblomst-relay -- <port> (0 picks a free one, the port used is printed)
+// players then use --relay <address> with --create <game name> or --join <game name>, --list shows the games waiting
+
+#![allow(dead_code)] // the relay only needs the lobby part of the protocol
+
+#[path = "../clock.rs"]
+mod clock;
+#[path = "../protocol.rs"]
+mod protocol;
+
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam::channel::{RecvTimeoutError, Sender};
+
+use protocol::Message;
+
+const ALIVE_CHECK: Duration = Duration::from_secs(1); // how often a waiting creator is checked for having left
+const FIRST_LINE_MAX: u64 = 4096; // a lobby message is short, anything longer isn't a player
+
+type Lobby = Arc<Mutex<HashMap<String, Sender<BufReader<TcpStream>>>>>; // waiting games, the sender hands the creator its opponent
+
+fn main() {
+    let port: u16 = env::args().nth(1).and_then(|port| port.parse().ok()).unwrap_or(0);
+    let listener = TcpListener::bind(("0.0.0.0", port)).expect("Failed to bind");
+    println!("Relay listening on port {}", listener.local_addr().expect("bound listener has an address").port());
+    let _ = io::stdout().flush(); // whoever started us may be waiting for the port
+
+    let lobby: Lobby = Arc::new(Mutex::new(HashMap::new()));
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let lobby = Arc::clone(&lobby);
+                thread::spawn(move || serve(stream, lobby));
+            }
+            Err(e) => println!("Connection failed: {}", e),
+        }
+    }
+}
+
+fn serve(stream: TcpStream, lobby: Lobby) { // reads what a player wants and either answers it or pairs them up
+    let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_else(|_| "?".to_string());
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    if (&mut reader).take(FIRST_LINE_MAX).read_line(&mut line).unwrap_or(0) == 0 {
+        return;
+    }
+    match protocol::decode(line.trim()) {
+        Ok(Message::ListGames) => {
+            let mut games: Vec<String> = lobby.lock().unwrap().keys().cloned().collect();
+            games.sort();
+            let _ = send(reader.get_mut(), &Message::Games { games });
+        }
+        Ok(Message::CreateGame { game }) => create(reader, game, &peer, &lobby),
+        Ok(Message::JoinGame { game }) => {
+            let creator = lobby.lock().unwrap().remove(&game);
+            match creator {
+                Some(creator) => {
+                    println!("{} joined '{}'", peer, game);
+                    if let Err(e) = creator.send(reader) { // left between the check and now
+                        println!("The creator of '{}' is gone", game);
+                        let _ = send(e.into_inner().get_mut(), &Message::RelayError { reason: format!("the player who created '{}' left", game) });
+                    }
+                }
+                None => {
+                    let _ = send(reader.get_mut(), &Message::RelayError { reason: format!("no game called '{}' is waiting for a player", game) });
+                }
+            }
+        }
+        Ok(other) => {
+            let _ = send(reader.get_mut(), &Message::RelayError { reason: format!("expected a game to create, join or list, got {:?}", other) });
+        }
+        Err(e) => {
+            println!("Bad first message from {}: {}", peer, e);
+            let _ = send(reader.get_mut(), &Message::RelayError { reason: e });
+        }
+    }
+}
+
+fn create(mut creator: BufReader<TcpStream>, game: String, peer: &str, lobby: &Lobby) { // waits for someone to join, then relays until either side leaves
+    let (joiner_tx, joiner_rx) = crossbeam::channel::bounded(1);
+    {
+        let mut lobby = lobby.lock().unwrap();
+        if lobby.contains_key(&game) {
+            drop(lobby);
+            let _ = send(creator.get_mut(), &Message::RelayError { reason: format!("a game called '{}' is already waiting", game) });
+            return;
+        }
+        lobby.insert(game.clone(), joiner_tx.clone());
+    }
+    println!("{} created '{}'", peer, game);
+
+    let mut joiner = loop {
+        match joiner_rx.recv_timeout(ALIVE_CHECK) {
+            Ok(joiner) => break joiner,
+            Err(RecvTimeoutError::Timeout) => {
+                if !still_there(creator.get_ref()) {
+                    let mut lobby = lobby.lock().unwrap();
+                    if lobby.get(&game).is_some_and(|waiting| waiting.same_channel(&joiner_tx)) {
+                        lobby.remove(&game);
+                    }
+                    drop(lobby);
+                    if let Ok(mut joiner) = joiner_rx.try_recv() { // someone got in just as the creator left
+                        let _ = send(joiner.get_mut(), &Message::RelayError { reason: format!("the player who created '{}' left", game) });
+                    }
+                    println!("{} left '{}' before anyone joined", peer, game);
+                    return;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    };
+
+    if send(creator.get_mut(), &Message::Paired { host: true }).is_err() || send(joiner.get_mut(), &Message::Paired { host: false }).is_err() {
+        println!("'{}' ended before it started", game);
+        shut(&creator, &joiner);
+        return;
+    }
+    println!("'{}' started", game);
+
+    let (creator_stream, joiner_stream) = match (creator.get_ref().try_clone(), joiner.get_ref().try_clone()) {
+        (Ok(creator_stream), Ok(joiner_stream)) => (creator_stream, joiner_stream),
+        _ => {
+            shut(&creator, &joiner);
+            return;
+        }
+    };
+    let backwards = thread::spawn(move || pipe(joiner, creator_stream));
+    pipe(creator, joiner_stream);
+    let _ = backwards.join();
+    println!("'{}' ended", game);
+}
+
+fn pipe(mut from: BufReader<TcpStream>, mut to: TcpStream) { // copies one direction, lines and all, and closes both sides when it ends
+    let _ = io::copy(&mut from, &mut to); // the reader hands over whatever it had buffered first
+    let _ = from.get_ref().shutdown(Shutdown::Both);
+    let _ = to.shutdown(Shutdown::Both);
+}
+
+fn shut(creator: &BufReader<TcpStream>, joiner: &BufReader<TcpStream>) {
+    let _ = creator.get_ref().shutdown(Shutdown::Both);
+    let _ = joiner.get_ref().shutdown(Shutdown::Both);
+}
+
+fn still_there(stream: &TcpStream) -> bool { // a closed connection reads as zero bytes, a waiting one has nothing to read yet
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let alive = match stream.peek(&mut [0u8; 1]) {
+        Ok(0) => false,
+        Ok(_) => true,
+        Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+    };
+    let _ = stream.set_nonblocking(false);
+    alive
+}
+
+fn send(stream: &mut TcpStream, message: &Message) -> io::Result<()> {
+    writeln!(stream, "{}", protocol::encode(message))?;
+    stream.flush()
+}
@@ -0,0 +1,266 @@
+// bridge.rs is the headless --uci-bridge mode: it speaks UCI on stdin/stdout, so a chess GUI or an engine harness
+// can play through it against a person running mblomst_gui on another machine. The harness plays our color
+// (the one agreed in the handshake), and every "go" is answered with the remote player's next move.
+// stdout belongs to the harness, so the connection code logs to stderr
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use crossbeam::channel;
+
+use chess::*;
+
+use crate::clock::Clocks;
+use crate::connection_state::ConnectionState;
+use crate::fen::{self, Castling};
+use crate::helper;
+use crate::move_piece;
+use crate::protocol::{Incoming, Message, MoveRecord};
+use crate::uci;
+
+struct Bridge {
+    state: Arc<Mutex<ConnectionState>>,
+    game: Game,
+    castling: Castling, // what the starting position allows
+    history: Vec<MoveRecord>,
+    unreported: VecDeque<MoveRecord>, // remote moves the harness hasn't asked for yet
+    searching: bool, // the harness sent "go" and waits for a bestmove
+    clocks: Option<Clocks>, // only the harness's side is kept, the remote player reports their own
+    turn_started: Instant, // when the harness's clock last started running
+}
+
+pub fn run(state: Arc<Mutex<ConnectionState>>) {
+    let (lines_tx, lines_rx) = channel::unbounded();
+    thread::spawn(move || { // stdin blocks, so it gets its own thread
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if lines_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    let incoming_rx = state.lock().unwrap().incoming_rx.clone();
+    let start_fen = state.lock().unwrap().start_fen.clone();
+    let mut bridge = Bridge {
+        state,
+        game: fen::start_game(start_fen.as_deref()),
+        castling: fen::castling_rights(start_fen.as_deref()),
+        history: Vec::new(),
+        unreported: VecDeque::new(),
+        searching: false,
+        clocks: None,
+        turn_started: Instant::now(),
+    };
+
+    loop {
+        crossbeam::select! {
+            recv(lines_rx) -> line => {
+                let Ok(line) = line else { break }; // the harness closed stdin
+                if !bridge.command(line.trim()) {
+                    break;
+                }
+            }
+            recv(incoming_rx) -> incoming => {
+                let Ok(incoming) = incoming else { break };
+                bridge.incoming(incoming);
+            }
+        }
+    }
+}
+
+fn reply(line: &str) { // a line to the harness, flushed right away since it waits for it
+    let mut stdout = io::stdout().lock();
+    let _ = writeln!(stdout, "{}", line);
+    let _ = stdout.flush();
+}
+
+impl Bridge {
+    fn command(&mut self, line: &str) -> bool { // handles one UCI command, false on quit
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("uci") => {
+                reply("id name mblomst bridge");
+                reply("id author mblomst");
+                reply("uciok");
+            }
+            Some("isready") => reply("readyok"),
+            Some("ucinewgame") if !self.history.is_empty() => {
+                reply("info string a new game needs a rematch in the remote gui, keeping the current one");
+            }
+            Some("position") => self.position(&words.collect::<Vec<_>>()),
+            Some("go") => {
+                self.searching = true;
+                self.report();
+            }
+            Some("stop") => {} // nothing to cut short, the answer comes when the remote player moves
+            Some("quit") => return false,
+            _ => {} // unknown commands are ignored, as UCI asks
+        }
+        true
+    }
+
+    fn position(&mut self, words: &[&str]) { // "startpos moves e2e4 ..." or "fen <six fields> moves ..."
+        let moves_at = words.iter().position(|&word| word == "moves");
+        let harness_start = match words.first() {
+            Some(&"fen") => words[1..moves_at.unwrap_or(words.len())].join(" "),
+            _ => fen::START_FEN.to_string(),
+        };
+        let agreed = self.state.lock().unwrap().start_fen.clone().unwrap_or_else(|| fen::START_FEN.to_string());
+        if harness_start.split_whitespace().take(4).ne(agreed.split_whitespace().take(4)) { // the move counters don't matter
+            reply("info string this position is not the one agreed with the remote player");
+        }
+        let moves: Vec<&str> = moves_at.map_or(Vec::new(), |at| words[at + 1..].to_vec());
+        let known: Vec<String> = self.history.iter().map(|record| uci::to_uci(*record)).collect();
+        if moves.len() <= known.len() { // nothing new, the harness may not have added our last reply yet
+            if !known.iter().zip(&moves).all(|(ours, theirs)| ours == theirs) {
+                reply("info string the harness's moves differ from the game with the remote player");
+            }
+            return;
+        }
+        if !known.iter().zip(&moves).all(|(ours, theirs)| ours == theirs) {
+            reply("info string the harness's moves differ from the game with the remote player");
+            return;
+        }
+        for text in &moves[known.len()..] { // the harness's own moves, played here and sent on
+            let Some(record) = uci::from_uci(text) else {
+                reply(&format!("info string can't read move {}", text));
+                return;
+            };
+            if self.remote_to_move() {
+                reply(&format!("info string {} was played for the remote player", text));
+                return;
+            }
+            let mover = self.game.player_tracker();
+            if !move_piece::play_quietly(&mut self.game, record, self.castling) {
+                reply(&format!("info string {} is not legal here", text));
+                return;
+            }
+            self.history.push(record);
+            let clock = self.clocks.as_mut().map(|clocks| {
+                clocks.tick(mover, self.turn_started.elapsed());
+                clocks.finish_move(mover)
+            });
+            self.send(Message::Move { from: record.from, to: record.to, promotion: record.promotion, hash: helper::position_hash(&self.game), clock });
+        }
+    }
+
+    fn resync(&mut self, moves: Vec<MoveRecord>) { // the host's moves replace ours, the harness hears about the ones it hasn't seen
+        let (start_fen, my_color) = {
+            let state = self.state.lock().unwrap();
+            (state.start_fen.clone(), state.my_color)
+        };
+        let common = self.history.iter().zip(&moves).take_while(|(ours, theirs)| ours == theirs).count();
+        let mut game = fen::start_game(start_fen.as_deref());
+        let mut remote_moves = Vec::new();
+        for (i, &record) in moves.iter().enumerate() {
+            let remote = Some(game.player_tracker()) != my_color;
+            if !move_piece::play_quietly(&mut game, record, self.castling) {
+                reply("info string the host's moves don't apply on the bridge's board");
+                return;
+            }
+            if i >= common && remote {
+                remote_moves.push(record);
+            }
+        }
+        if common < self.history.len() {
+            reply("info string the host's board replaced ours, the harness's last moves were undone");
+            self.unreported.clear();
+        }
+        self.unreported.extend(remote_moves);
+        self.game = game;
+        self.history = moves;
+        self.turn_started = Instant::now();
+        self.report();
+    }
+
+    fn remote_to_move(&self) -> bool {
+        self.state.lock().unwrap().my_color.is_some_and(|color| color != self.game.player_tracker())
+    }
+
+    fn report(&mut self) { // answers a pending "go" once the remote player has moved
+        if !self.searching {
+            return;
+        }
+        if let Some(record) = self.unreported.pop_front() {
+            self.searching = false;
+            reply(&format!("bestmove {}", uci::to_uci(record)));
+        }
+    }
+
+    fn send(&self, msg: Message) {
+        let tx = self.state.lock().unwrap().outgoing_tx.clone();
+        let _ = tx.send(msg); // waits in the channel while the link is down
+    }
+
+    fn incoming(&mut self, incoming: Incoming) {
+        match incoming {
+            Incoming::Connected => {
+                let (color, start_fen, time_control) = {
+                    let state = self.state.lock().unwrap();
+                    (state.my_color.map(helper::color_name).unwrap_or("?"), state.start_fen.clone(), state.time_control)
+                };
+                if self.history.is_empty() { // the host may have picked another starting position
+                    self.game = fen::start_game(start_fen.as_deref());
+                    self.castling = fen::castling_rights(start_fen.as_deref());
+                }
+                if let (None, Some(time_control)) = (&self.clocks, time_control) { // the clock keeps its time through a reconnect
+                    self.clocks = Some(Clocks::new(time_control));
+                }
+                self.turn_started = Instant::now();
+                reply(&format!("info string connected, the harness plays {}", color));
+                self.send(Message::Resume { moves: self.history.clone() });
+            }
+            Incoming::Message(Message::Move { from, to, promotion, hash, .. }) => {
+                let record = MoveRecord { from, to, promotion };
+                if !self.remote_to_move() || !move_piece::play_quietly(&mut self.game, record, self.castling) {
+                    self.send(Message::IllegalMove { from, to, reason: "not a legal move on the bridge's board".to_string() });
+                    return;
+                }
+                if helper::position_hash(&self.game) != hash {
+                    reply("info string the boards disagree after the remote player's move");
+                }
+                self.history.push(record);
+                self.unreported.push_back(record);
+                self.turn_started = Instant::now();
+                self.report();
+            }
+            Incoming::Message(Message::Resume { moves }) => { // the remote player got further while the link was down
+                if moves.len() > self.history.len() && moves.starts_with(&self.history) {
+                    for &record in &moves[self.history.len()..] {
+                        if !move_piece::play_quietly(&mut self.game, record, self.castling) {
+                            reply("info string could not catch up with the remote player's moves");
+                            return;
+                        }
+                        self.history.push(record);
+                        self.unreported.push_back(record);
+                    }
+                    self.turn_started = Instant::now();
+                    self.report();
+                }
+            }
+            Incoming::Message(Message::ResyncRequest) => {
+                if self.state.lock().unwrap().is_host { // our board is the one that counts
+                    self.send(Message::Resync { moves: self.history.clone() });
+                }
+            }
+            Incoming::Message(Message::Resync { moves }) => {
+                if !self.state.lock().unwrap().is_host {
+                    self.resync(moves);
+                }
+            }
+            Incoming::Message(Message::Resign) => reply("info string the remote player resigned"),
+            Incoming::Message(Message::Timeout { color }) => reply(&format!("info string {:?} ran out of time", color)),
+            Incoming::Message(Message::DrawOffer) => self.send(Message::DrawDecline), // the harness can't be asked
+            Incoming::Message(Message::RematchOffer) => self.send(Message::RematchDecline),
+            Incoming::Message(Message::Chat { text, .. }) => reply(&format!("info string remote player: {}", text)),
+            Incoming::Message(Message::IllegalMove { from, to, reason }) => {
+                reply(&format!("info string the remote player refused {}: {}", uci::to_uci(MoveRecord { from, to, promotion: None }), reason));
+            }
+            Incoming::Message(_) => {}
+            Incoming::Malformed(report) => reply(&format!("info string {}", report)),
+        }
+    }
+}
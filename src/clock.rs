@@ -0,0 +1,100 @@
+// clock.rs keeps the two chess clocks, the time control itself is agreed on in the handshake
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use chess::piece::Color as ChessColor;
+
+const MAX_BASE_MINUTES: f64 = 24.0 * 60.0; // a day on the clock is already more than any game needs
+const MAX_INCREMENT_SECONDS: f64 = 60.0 * 60.0;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct TimeControl {
+    pub base_ms: u64, // time on each clock when the game starts
+    pub increment_ms: u64, // added to the mover's clock after every move
+}
+
+impl TimeControl {
+    /// Parse the "minutes+increment seconds" form, e.g. "5+3". A lone number means no increment.
+    /// Anything that isn't a plain number, rounds down to no time at all or is absurdly long is refused.
+    pub fn parse(text: &str) -> Option<TimeControl> {
+        let (base, increment) = match text.split_once('+') {
+            Some((base, increment)) => (base, increment),
+            None => (text, "0"),
+        };
+        let base: f64 = base.trim().parse().ok()?;
+        let increment: f64 = increment.trim().parse().ok()?;
+        let sensible = base > 0.0 && base <= MAX_BASE_MINUTES && (0.0..=MAX_INCREMENT_SECONDS).contains(&increment); // false for nan and inf too
+        if !sensible {
+            return None;
+        }
+        let time_control = TimeControl { base_ms: (base * 60_000.0) as u64, increment_ms: (increment * 1000.0) as u64 };
+        (time_control.base_ms > 0).then_some(time_control)
+    }
+}
+
+impl std::fmt::Display for TimeControl { // written the way --time takes it
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}+{}", self.base_ms as f64 / 60_000.0, self.increment_ms as f64 / 1000.0)
+    }
+}
+
+pub struct Clocks {
+    pub time_control: TimeControl,
+    white_ms: i64, // signed so a flag that fell a little while ago can still be told apart from one that just fell
+    black_ms: i64,
+}
+
+impl Clocks {
+    pub fn new(time_control: TimeControl) -> Clocks {
+        Clocks {
+            time_control,
+            white_ms: time_control.base_ms as i64,
+            black_ms: time_control.base_ms as i64,
+        }
+    }
+
+    fn clock_mut(&mut self, color: ChessColor) -> &mut i64 {
+        match color {
+            ChessColor::White => &mut self.white_ms,
+            ChessColor::Black => &mut self.black_ms,
+        }
+    }
+
+    pub fn remaining(&self, color: ChessColor) -> i64 {
+        match color {
+            ChessColor::White => self.white_ms,
+            ChessColor::Black => self.black_ms,
+        }
+    }
+
+    /// Run `color`'s clock down by the time the last frame took.
+    pub fn tick(&mut self, color: ChessColor, elapsed: Duration) {
+        *self.clock_mut(color) -= elapsed.as_millis() as i64;
+    }
+
+    /// Add the increment for a move `color` just made and return what is left on that clock, which goes along with the move.
+    pub fn finish_move(&mut self, color: ChessColor) -> u64 {
+        let increment = self.time_control.increment_ms as i64;
+        let clock = self.clock_mut(color);
+        *clock = (*clock).max(0) + increment;
+        *clock as u64
+    }
+
+    /// Take over the time the second player reported for their own clock.
+    pub fn set(&mut self, color: ChessColor, remaining_ms: u64) {
+        *self.clock_mut(color) = remaining_ms as i64;
+    }
+}
+
+pub fn format_clock(remaining_ms: i64) -> String { // m:ss, with tenths in the last ten seconds
+    let remaining_ms = remaining_ms.max(0);
+    if remaining_ms < 10_000 {
+        format!("0:{:02}.{}", remaining_ms / 1000, (remaining_ms % 1000) / 100)
+    }
+    else {
+        let seconds = remaining_ms / 1000;
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
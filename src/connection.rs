@@ -1,41 +1,54 @@
-use std::net::{TcpListener, TcpStream};
-use std::io::{BufReader, BufRead, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::io::{BufReader, BufRead, ErrorKind, Read, Write};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::panic;
 use std::time::Duration;
 
+use crossbeam::channel::Sender;
+
 use crate::connection_state::ConnectionState;
+use crate::fen::Fen;
+use crate::protocol::{self, Incoming, Message, Side, PROTOCOL_VERSION};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Peer { // who is at the other end once the hellos are exchanged
+    Player,
+    Spectator,
+}
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(2); // how long the client waits between attempts to reach the host
+const PING_EVERY: Duration = Duration::from_secs(5); // a quiet link is pinged this often so the other side knows we are there
+const LINK_TIMEOUT: Duration = Duration::from_secs(15); // this long without a word from the other side and the link counts as dropped
+const GAME_FULL: &str = "the game already has two players, use --spectate to watch it";
 
 pub fn start_server(port: u16, connection_state: Arc<Mutex<ConnectionState>>) { // starts the player called "server"
     let address = format!("0.0.0.0:{}", port);
     let listener = TcpListener::bind(address).expect("Failed to bind");
+    connection_state.lock().unwrap().status = Some(format!("Waiting for an opponent on port {}", port));
+    connection_state.lock().unwrap().turn = 1;
 
-
-    for stream in listener.incoming().take(1) {
+    for stream in listener.incoming() { // keeps listening so a client that dropped out can come back, and for spectators
         match stream {
             Ok(stream) => {
-                println!("Client connected"); // wait for a "client" to connect
-
-                {
+                eprintln!("Client connected"); // wait for a "client" to connect
+                let connection_state = Arc::clone(&connection_state);
+                let from = stream.peer_addr().ok();
+                thread::spawn(move || { // every connection gets its own thread, spectators join while the game goes on
+                    let _ = run_connection(stream, Arc::clone(&connection_state), "Host");
                     let mut state = connection_state.lock().unwrap();
-                    state.connected = true;
-                    state.stream = Some(stream.try_clone().unwrap());
-                    state.turn = 1; 
-                }
-
-                let state_clone = Arc::clone(&connection_state);
-                thread::spawn(move || {
-                    let result = panic::catch_unwind(|| {
-                        handle_connection(stream, state_clone);
-                    });
-                    if let Err(e) = result {
-                        println!("[Host] panic in thread: {:?}", e);
+                    // only the link holding the game frees it, not spectators, refused players or an old link that was taken over
+                    if state.connected && from.is_some() && state.opponent_addr == from {
+                        state.connected = false;
+                        state.stream = None;
+                        state.status = Some(format!("Opponent disconnected, waiting for them to reconnect on port {}", port));
                     }
                 });
             }
             Err(e) => {
-                println!("Connection failed: {}", e);
+                eprintln!("Connection failed: {}", e);
             }
         }
     }
@@ -44,95 +57,414 @@ pub fn start_server(port: u16, connection_state: Arc<Mutex<ConnectionState>>) {
 
 
 pub fn start_client(addr: &str, connection_state: Arc<Mutex<ConnectionState>>) { // starts the player called "client"
-    match TcpStream::connect(addr) {
-        Ok(stream) => {
+    connection_state.lock().unwrap().status = Some(format!("Connecting to {}...", addr));
+    loop { // retries until the handshake is refused, a dropped link is picked up again
+        match TcpStream::connect(addr) {
+            Ok(stream) => {
 
-            {
+                {
+                    let mut state = connection_state.lock().unwrap();
+                    state.connected = true;
+                    state.stream = Some(stream.try_clone().unwrap());
+                }
+
+                let result = run_connection(stream, Arc::clone(&connection_state), "Client");
                 let mut state = connection_state.lock().unwrap();
-                state.connected = true;
-                state.stream = Some(stream.try_clone().unwrap());
+                state.connected = false;
+                state.stream = None;
+                match result {
+                    Err(e) if e == GAME_FULL => state.status = Some(format!("The game at {} already has two players, trying again...", addr)), // the seat may free up
+                    Err(_) => return, // another try would be refused the same way
+                    Ok(_) => state.status = Some(format!("Connection lost, reconnecting to {}...", addr)),
+                }
             }
+            Err(e) => {
+                eprintln!("Failed to connect: {}", e);
+            }
+        }
+        thread::sleep(RECONNECT_DELAY);
+    }
+}
 
-            let state_for_thread = Arc::clone(&connection_state);
-            thread::spawn(move || {
-                let result = panic::catch_unwind(|| {
-                    handle_connection(stream, state_for_thread);
+pub fn start_relayed(addr: &str, lobby: Message, connection_state: Arc<Mutex<ConnectionState>>) { // plays through a mblomst-relay, which decides who hosts
+    let game = match &lobby {
+        Message::CreateGame { game } | Message::JoinGame { game } => game.clone(),
+        _ => String::new(),
+    };
+    connection_state.lock().unwrap().status = Some(format!("Connecting to the relay at {}...", addr));
+    let mut played = false; // once the game has been on, a missing game means the creator hasn't come back yet
+    loop {
+        match TcpStream::connect(addr) {
+            Ok(mut stream) => {
+                connection_state.lock().unwrap().status = Some(match lobby {
+                    Message::CreateGame { .. } => format!("Waiting for someone to join '{}'", game),
+                    _ => format!("Joining '{}'...", game),
                 });
-                if let Err(e) = result {
-                    println!("[Client] panic in thread: {:?}", e);
+                match enter_lobby(&mut stream, &lobby) {
+                    Ok(is_host) => {
+                        {
+                            let mut state = connection_state.lock().unwrap();
+                            state.is_host = is_host;
+                            if !is_host { // the host takes its place in the handshake
+                                state.connected = true;
+                                state.stream = Some(stream.try_clone().unwrap());
+                            }
+                        }
+                        played = true;
+                        let refused = run_connection(stream, Arc::clone(&connection_state), if is_host { "Host" } else { "Client" }).is_err();
+                        let mut state = connection_state.lock().unwrap();
+                        state.connected = false;
+                        state.stream = None;
+                        if refused {
+                            return;
+                        }
+                        state.status = Some(format!("Connection lost, going back to '{}' on the relay...", game));
+                    }
+                    Err(Some(reason)) if !played => {
+                        eprintln!("The relay refused: {}", reason);
+                        connection_state.lock().unwrap().status = Some(format!("Relay: {}", reason));
+                        return;
+                    }
+                    Err(Some(reason)) => eprintln!("The relay refused: {}", reason),
+                    Err(None) => eprintln!("Lost the relay at {}", addr),
                 }
-            });
+            }
+            Err(e) => {
+                eprintln!("Failed to connect: {}", e);
+            }
         }
-        Err(e) => {
-            println!("Failed to connect: {}", e);
+        thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+pub fn list_games(addr: &str) -> Result<Vec<String>, String> { // asks a relay which games are waiting for a second player
+    let mut stream = TcpStream::connect(addr).map_err(|e| format!("could not reach the relay at {} ({})", addr, e))?;
+    send_message(&mut stream, &Message::ListGames)?;
+    let line = read_lobby_line(&mut stream).map_err(|e| format!("could not read from the relay ({})", e))?;
+    match protocol::decode(line.trim())? {
+        Message::Games { games } => Ok(games),
+        other => Err(format!("expected the list of games, got {:?}", other)),
+    }
+}
+
+fn enter_lobby(stream: &mut TcpStream, lobby: &Message) -> Result<bool, Option<String>> { // waits until the relay pairs us, Err(None) if the link broke
+    send_message(stream, lobby).map_err(|_| None)?;
+    let line = read_lobby_line(stream).map_err(|_| None)?;
+    match protocol::decode(line.trim()) {
+        Ok(Message::Paired { host }) => Ok(host),
+        Ok(Message::RelayError { reason }) => Err(Some(reason)),
+        Ok(other) => Err(Some(format!("expected to be paired, got {:?}", other))),
+        Err(e) => Err(Some(e)),
+    }
+}
+
+fn read_lobby_line(stream: &mut TcpStream) -> std::io::Result<String> { // a byte at a time, the opponent's hello may follow right behind it
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        if byte[0] == b'\n' {
+            return Ok(String::from_utf8_lossy(&line).into_owned());
         }
+        line.push(byte[0]);
     }
 }
 
-pub fn handle_connection(mut stream: TcpStream, state: Arc<Mutex<ConnectionState>>) {
-    stream.set_read_timeout(None); // makes read_line not time out while waiting for a move
+fn run_connection(stream: TcpStream, state: Arc<Mutex<ConnectionState>>, role: &str) -> Result<Peer, String> { // runs one connection on its own thread and waits for it to end
+    let handle = thread::spawn(move || {
+        panic::catch_unwind(|| handle_connection(stream, state))
+    });
+    match handle.join() {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) | Err(e) => {
+            eprintln!("[{}] panic in thread: {:?}", role, e);
+            Err("the connection thread panicked".to_string())
+        }
+    }
+}
+
+/// Talks to the second player, or to a spectator on the host, until the link drops. Returns an error if the handshake
+/// was refused, in which case reconnecting won't help.
+fn handle_connection(mut stream: TcpStream, state: Arc<Mutex<ConnectionState>>) -> Result<Peer, String> {
+    let _ = stream.set_read_timeout(None); // the handshake may wait on a person, the game sets its own timeout once it starts
 
     let mut reader = BufReader::new(stream.try_clone().unwrap());
     let is_host = state.lock().unwrap().is_host;
     let role = if is_host { "Host" } else { "Client" };
 
-    let outgoing_rx = {
-        let state = state.lock().unwrap();
-        state.outgoing_rx.clone()
+    let peer = match handshake(&mut stream, &mut reader, &state) { // nothing is played until both sides agree on versions and colors
+        Ok(peer) => peer,
+        Err(e) => {
+            eprintln!("[{}] Handshake failed: {}", role, e);
+            {
+                let mut state = state.lock().unwrap();
+                if !(is_host && state.connected) { // turning away a third player doesn't concern the game in progress
+                    state.connected = false;
+                    state.status = Some(format!("Connection refused: {}", e));
+                }
+            }
+            let _ = stream.shutdown(Shutdown::Both);
+            return Err(e);
+        }
     };
+    if is_host && peer == Peer::Spectator {
+        watch(stream, reader, &state);
+        return Ok(peer);
+    }
 
-    let incoming_tx = {
+    let _ = stream.set_read_timeout(Some(LINK_TIMEOUT)); // a link that died without closing is noticed by the pings stopping
+    let (outgoing_tx, outgoing_rx, incoming_tx) = {
         let state = state.lock().unwrap();
-        state.incoming_tx.clone()
+        (state.outgoing_tx.clone(), state.outgoing_rx.clone(), state.incoming_tx.clone())
     };
+    if let Err(e) = incoming_tx.send(Incoming::Connected) { // lets the gui compare move lists with the second player
+        eprintln!("[{}] Failed to push to incoming_tx: {}", role, e);
+    }
 
-    loop {
+    // reading happens on its own thread so messages are picked up whenever they arrive, whoevers turn it is
+    let (closed_tx, closed_rx) = crossbeam::channel::bounded::<()>(1);
+    let reader_role = role.to_string();
+    let reader_thread = thread::spawn(move || {
+        read_messages(reader, incoming_tx, outgoing_tx, &reader_role);
+        let _ = closed_tx.send(()); // wakes up the writer below
+    });
 
-        // called for writing messages
-        while let Ok(msg) = outgoing_rx.try_recv() {
-            if let Err(e) = writeln!(stream, "{}", msg) {
-                println!("[{}] Failed to write to stream: {}", role, e);
-                break;
+    // writing waits on both the gui's messages and the reader, so nothing spins while the game is quiet
+    loop {
+        crossbeam::select! {
+            recv(outgoing_rx) -> msg => {
+                let Ok(msg) = msg else { break };
+                if let Err(e) = send_message(&mut stream, &msg) {
+                    eprintln!("[{}] {}", role, e);
+                    break;
+                }
             }
-            if let Err(e) = stream.flush() {
-                println!("[{}] Failed to flush stream: {}", role, e);
-                break;
+            recv(closed_rx) -> _ => break,
+            default(PING_EVERY) => {
+                if let Err(e) = send_message(&mut stream, &Message::Ping) {
+                    eprintln!("[{}] {}", role, e);
+                    break;
+                }
             }
         }
+    }
 
-        // called for reading messages
-        let should_listen = { // only listens if it is the opponents turn
-            let state = state.lock().unwrap();
-            (state.turn % 2 == 1) != state.is_host
-        };
+    let _ = stream.shutdown(Shutdown::Both); // unblocks the reader if it was the writer that failed
+    let _ = reader_thread.join();
+    Ok(peer)
+}
+
+fn watch(mut stream: TcpStream, mut reader: BufReader<TcpStream>, state: &Arc<Mutex<ConnectionState>>) { // sends a spectator the game so far and then every change to it, until they leave
+    let (tx, rx) = crossbeam::channel::unbounded();
+    let pong_tx = tx.clone();
+    {
+        let mut state = state.lock().unwrap();
+        if let Some((view, clocks)) = &state.watched {
+            let _ = tx.send(Message::Spectate { view: view.clone(), clocks: *clocks });
+        }
+        state.spectators.push(tx.clone());
+    }
+    eprintln!("[Host] A spectator joined");
 
-        if should_listen {
-            let mut line = String::new();
+    let (closed_tx, closed_rx) = crossbeam::channel::bounded::<()>(1);
+    let reader_thread = thread::spawn(move || { // spectators have nothing to say, this only notices them leaving
+        let mut line = String::new();
+        loop {
+            line.clear();
             match reader.read_line(&mut line) {
-                Ok(0) => {
-                    println!("[{}] Connection closed by peer", role);
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if let Ok(Message::Ping) = protocol::decode(line.trim()) {
+                        let _ = pong_tx.send(Message::Pong);
+                    }
+                }
+            }
+        }
+        let _ = closed_tx.send(());
+    });
+
+    loop {
+        crossbeam::select! {
+            recv(rx) -> msg => {
+                let Ok(msg) = msg else { break };
+                if let Err(e) = send_message(&mut stream, &msg) {
+                    eprintln!("[Host] spectator: {}", e);
                     break;
                 }
-                Ok(n) => { // if there is something to read
-                    let msg = line.trim().to_string();
-                    if !msg.is_empty() {
-                        if let Err(e) = incoming_tx.send(msg.clone()) { //tries to send the message to incoming
-                            println!("[{}] Failed to push to incoming_tx: {}", role, e);
-                        } else {
+            }
+            recv(closed_rx) -> _ => break,
+        }
+    }
+
+    let _ = stream.shutdown(Shutdown::Both);
+    let _ = reader_thread.join();
+    state.lock().unwrap().spectators.retain(|spectator| !spectator.same_channel(&tx));
+    eprintln!("[Host] A spectator left");
+}
+
+fn read_messages(mut reader: BufReader<TcpStream>, incoming_tx: Sender<Incoming>, outgoing_tx: Sender<Message>, role: &str) { // hands every line from the second player to the gui until the link closes
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) => {
+                eprintln!("[{}] Connection closed by peer", role);
+                return;
+            }
+            Ok(_) => { // if there is something to read
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                let incoming = match protocol::decode(line) {
+                    Ok(Message::Ping) => { // answered right here, the gui never needs to see it
+                        if let Err(e) = outgoing_tx.send(Message::Pong) {
+                            eprintln!("[{}] Failed to queue pong: {}", role, e);
                         }
+                        continue;
                     }
+                    Ok(Message::Pong) => continue, // only says the link is alive, which reading it already showed
+                    Ok(msg) => Incoming::Message(msg),
+                    Err(e) => {
+                        eprintln!("[{}] Received bad message {:?}: {}", role, line, e);
+                        Incoming::Malformed(format!("Bad message from opponent: {}", e))
+                    }
+                };
+                if let Err(e) = incoming_tx.send(incoming) { //tries to send the message to incoming
+                    eprintln!("[{}] Failed to push to incoming_tx: {}", role, e);
+                    return;
                 }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    // if timeout, in case of no data available, continue loop
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                eprintln!("[{}] Nothing heard from the other side in {} seconds, dropping the link", role, LINK_TIMEOUT.as_secs());
+                return;
+            }
+            Err(e) => {
+                eprintln!("[{}] Error reading from stream: {}", role, e);
+                return;
+            }
+        }
+    }
+}
+
+
+/// Exchange hellos with the second player, refuse incompatible builds and settle who plays which color.
+/// Spectators only exchange hellos, the game reaches them afterwards.
+fn handshake(stream: &mut TcpStream, reader: &mut BufReader<TcpStream>, state: &Arc<Mutex<ConnectionState>>) -> Result<Peer, String> {
+    let (is_host, name, color_choice, time_control, start_fen, spectator, session) = {
+        let state = state.lock().unwrap();
+        let session = if state.is_host { None } else { state.session }; // the host's own is the one it handed out
+        (state.is_host, state.name.clone(), state.color_choice, state.time_control, state.start_fen.clone(), state.spectator, session)
+    };
+
+    send_message(stream, &Message::Hello { name, color: color_choice, time_control, spectator, session })?;
+    let (opponent_name, opponent_choice, opponent_time_control, opponent_watches, opponent_session) = match read_handshake_message(reader)? {
+        Message::Hello { name, color, time_control, spectator, session } => (name, color, time_control, spectator, session),
+        other => return Err(format!("expected a hello from the opponent, got {:?}", other)),
+    };
+    if spectator {
+        eprintln!("Watching {}'s game", opponent_name);
+        state.lock().unwrap().status = None;
+        return Ok(Peer::Player); // the host is the only one we talk to
+    }
+    if is_host && opponent_watches {
+        eprintln!("{} is watching", opponent_name);
+        return Ok(Peer::Spectator);
+    }
+
+    let from = stream.peer_addr().ok();
+    let mut session = None;
+    if is_host {
+        let state = state.lock().unwrap();
+        if state.connected && !reclaims(&state, opponent_session) {
+            drop(state);
+            let _ = send_message(stream, &Message::GameFull);
+            return Err(GAME_FULL.to_string());
+        }
+        session = match opponent_session { // the seat keeps its session, a new player gets a new one
+            Some(returning) if state.session == Some(returning) => Some(returning),
+            _ => Some(RandomState::new().build_hasher().finish()),
+        };
+    }
+
+    let (my_side, time_control, start_fen) = if is_host { // the host decides and tells the client
+        let agreed = state.lock().unwrap().my_color;
+        let side = match agreed {
+            Some(color) => Side::from_chess(color), // a reconnect keeps the colors of the game in progress
+            None => {
+                let coin = RandomState::new().build_hasher().finish().is_multiple_of(2);
+                protocol::resolve_colors(color_choice, opponent_choice, coin)
+            }
+        };
+        let time_control = time_control.or(opponent_time_control); // the host's clock if it asked for one, otherwise the client's
+        send_message(stream, &Message::Setup { color: side.opposite(), time_control, fen: start_fen.clone(), session })?;
+        (side, time_control, start_fen)
+    } else {
+        match read_handshake_message(reader)? {
+            Message::Setup { color, time_control, fen, session: given } => {
+                if let Some(text) = &fen {
+                    Fen::parse(text).map_err(|e| format!("the host's starting position is not valid FEN ({})", e))?;
                 }
-                Err(e) => {
-                    println!("[{}] Error reading from stream: {}", role, e);
-                    break;
+                if start_fen.is_some() && fen != start_fen {
+                    eprintln!("Using the host's starting position instead of ours");
                 }
+                session = given;
+                (color, time_control, fen)
             }
+            Message::GameFull => return Err(GAME_FULL.to_string()),
+            other => return Err(format!("expected the game setup from the host, got {:?}", other)),
         }
+    };
 
-        thread::sleep(Duration::from_millis(20)); // gives a bit of time for the loop to catch up and minimizes risk for errors with reading
+    match time_control {
+        Some(time_control) => eprintln!("Playing {:?} against {} on a {} clock", my_side, opponent_name, time_control),
+        None => eprintln!("Playing {:?} against {}", my_side, opponent_name),
+    }
+    let mut state = state.lock().unwrap();
+    if is_host { // the first player through the handshake gets the game
+        if state.connected {
+            if !reclaims(&state, opponent_session) { // another player got in during the handshake
+                return Err(GAME_FULL.to_string());
+            }
+            if let Some(old) = state.stream.take() {
+                let _ = old.shutdown(Shutdown::Both); // ends the old link, which leaves the game to this one
+            }
+        }
+        state.connected = true;
+        state.stream = stream.try_clone().ok();
+        state.opponent_addr = from;
     }
+    state.my_color = Some(my_side.to_chess());
+    state.opponent_name = Some(opponent_name);
+    state.time_control = time_control;
+    state.start_fen = start_fen;
+    state.session = session;
+    state.status = None;
+    Ok(Peer::Player)
 }
 
+fn reclaims(state: &ConnectionState, session: Option<u64>) -> bool { // the player the game is with, back on a new link before the host noticed the old one drop
+    session.is_some() && session == state.session
+}
+
+fn read_handshake_message(reader: &mut BufReader<TcpStream>) -> Result<Message, String> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) => return Err("the opponent closed the connection".to_string()),
+        Ok(_) => {}
+        Err(e) => return Err(format!("could not read from the opponent ({})", e)),
+    }
+    match protocol::peek_version(line.trim()) {
+        Some(v) if v != PROTOCOL_VERSION => return Err(format!("the opponent runs protocol version {}, this build runs {}", v, PROTOCOL_VERSION)),
+        Some(_) => {}
+        None => return Err("the opponent does not speak the mblomst protocol".to_string()),
+    }
+    protocol::decode(line.trim())
+}
+
+fn send_message(stream: &mut TcpStream, message: &Message) -> Result<(), String> {
+    writeln!(stream, "{}", protocol::encode(message))
+        .and_then(|_| stream.flush())
+        .map_err(|e| format!("could not write to the opponent ({})", e))
+}
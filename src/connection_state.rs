@@ -1,20 +1,37 @@
-use std::net::TcpStream;
+use std::net::{SocketAddr, TcpStream};
 use crossbeam::channel::{Sender, Receiver};
 
+use chess::piece::Color as ChessColor;
+
+use crate::clock::TimeControl;
+use crate::protocol::{ColorChoice, GameView, Incoming, Message};
+
 pub struct ConnectionState {
-    pub outgoing_tx: Sender<String>,
-    pub outgoing_rx: Receiver<String>,
-    pub incoming_tx: Sender<String>,
-    pub incoming_rx: Receiver<String>,
+    pub outgoing_tx: Sender<Message>,
+    pub outgoing_rx: Receiver<Message>,
+    pub incoming_tx: Sender<Incoming>,
+    pub incoming_rx: Receiver<Incoming>,
     pub stream: Option<TcpStream>,
     pub connected: bool,
     pub is_host: bool,
     pub turn: usize,
+    pub name: String,
+    pub color_choice: ColorChoice, // the color asked for in the handshake
+    pub my_color: Option<ChessColor>, // the color agreed on in the handshake, None until then
+    pub opponent_name: Option<String>,
+    pub opponent_addr: Option<SocketAddr>, // host only, where the player holding the game connected from
+    pub session: Option<u64>, // handed out by the host in the Setup, lets the client take its seat back after a drop
+    pub time_control: Option<TimeControl>, // asked for with --time, replaced by the agreed one in the handshake
+    pub status: Option<String>, // shown in the gui while something is wrong with the connection
+    pub start_fen: Option<String>, // asked for with --fen, replaced by the host's in the handshake, None is the normal start
+    pub spectator: bool, // joined with --spectate, watches the game without playing
+    pub spectators: Vec<Sender<Message>>, // host only, one for every connection watching the game
+    pub watched: Option<(GameView, Option<(u64, u64)>)>, // host only, the game and clocks a spectator gets when joining
 }
 
 impl ConnectionState { // creates the connection sate
     pub fn new() -> Self {
-        println!("ConnectionState created");
+        eprintln!("ConnectionState created");
         let (outgoing_tx, outgoing_rx) = crossbeam::channel::unbounded();
         let (incoming_tx, incoming_rx) = crossbeam::channel::unbounded();
 
@@ -27,6 +44,22 @@ impl ConnectionState { // creates the connection sate
             connected: false,
             is_host: false,
             turn: 0,
+            name: "Player".to_string(),
+            color_choice: ColorChoice::Random,
+            my_color: None,
+            opponent_name: None,
+            opponent_addr: None,
+            session: None,
+            time_control: None,
+            status: None,
+            start_fen: None,
+            spectator: false,
+            spectators: Vec::new(),
+            watched: None,
         }
     }
+
+    pub fn tell_spectators(&mut self, message: Message) { // spectators that left are dropped on the way
+        self.spectators.retain(|tx| tx.send(message.clone()).is_ok());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,85 @@
+// discovery.rs finds games on the local network, hosts broadcast a small JSON datagram every few seconds and --browse listens for them
+
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock::TimeControl;
+use crate::connection_state::ConnectionState;
+use crate::protocol::PROTOCOL_VERSION;
+use crate::worker::Worker;
+
+pub const DISCOVERY_PORT: u16 = 47474; // every host announces to this port, browsers listen on it
+const ANNOUNCE_EVERY: Duration = Duration::from_secs(2);
+const STOP_CHECK: Duration = Duration::from_millis(250); // how often a quiet listener checks whether anyone still wants to hear
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Announcement { // what a host tells the network about its game
+    pub v: u32, // the protocol version, only games we can join are listed
+    pub name: String,
+    pub port: u16,
+    pub time_control: Option<TimeControl>,
+    pub playing: bool, // someone is already playing the host, joining means watching
+}
+
+#[derive(Debug, Clone)]
+pub struct Host { // an announcement and where it came from
+    pub address: SocketAddr, // the sender's ip with the announced port, ready for --connect
+    pub announcement: Announcement,
+}
+
+
+pub fn announce(port: u16, state: Arc<Mutex<ConnectionState>>) { // broadcasts the game until the program ends
+    let socket = match UdpSocket::bind(("0.0.0.0", 0)).and_then(|socket| socket.set_broadcast(true).map(|_| socket)) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("Not announcing the game on the local network: {}", e);
+            return;
+        }
+    };
+    loop {
+        let announcement = {
+            let state = state.lock().unwrap();
+            Announcement { v: PROTOCOL_VERSION, name: state.name.clone(), port, time_control: state.time_control, playing: state.connected }
+        };
+        let datagram = serde_json::to_vec(&announcement).expect("announcements always serialize");
+        if let Err(e) = socket.send_to(&datagram, ("255.255.255.255", DISCOVERY_PORT)) {
+            eprintln!("Failed to announce the game: {}", e);
+        }
+        thread::sleep(ANNOUNCE_EVERY);
+    }
+}
+
+/// Listen for hosts announcing their games. Every announcement heard arrives on the receiver, so a host shows up
+/// again every couple of seconds for as long as it is there. Dropping the Worker stops the listening and frees the port.
+pub fn listen() -> Result<Worker<Host>, String> {
+    let socket = UdpSocket::bind(("0.0.0.0", DISCOVERY_PORT))
+        .and_then(|socket| socket.set_read_timeout(Some(STOP_CHECK)).map(|_| socket))
+        .map_err(|e| format!("could not listen on port {} ({})", DISCOVERY_PORT, e))?;
+    Ok(Worker::start(move |tx, stop| {
+        let mut buffer = [0u8; 1024];
+        while !stop.load(Ordering::Relaxed) {
+            let (length, from) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue, // nothing heard, time to check on the browser
+                Err(e) => {
+                    eprintln!("Stopped listening for games: {}", e);
+                    return;
+                }
+            };
+            let Ok(announcement) = serde_json::from_slice::<Announcement>(&buffer[..length]) else { continue }; // someone else's datagram
+            if announcement.v != PROTOCOL_VERSION {
+                continue;
+            }
+            let host = Host { address: SocketAddr::new(from.ip(), announcement.port), announcement };
+            if tx.send(host).is_err() { // nobody is browsing anymore
+                return;
+            }
+        }
+    }))
+}
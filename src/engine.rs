@@ -0,0 +1,233 @@
+// engine.rs is a small built-in computer opponent: alpha-beta search over the chess crate's moves,
+// scored by material plus piece-square tables (the "simplified evaluation function")
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use crossbeam::channel::{self, Receiver};
+
+use chess::*;
+use chess::game::GameResult;
+use chess::piece::{Color as ChessColor, Piece};
+use chess::position::get_piece_at;
+
+use crate::analysis::{Analysis, Score};
+use crate::fen::Castling;
+use crate::helper::piece_to_char;
+use crate::move_piece;
+use crate::notation;
+use crate::protocol::MoveRecord;
+use crate::worker::Worker;
+
+const MATE: i32 = 1_000_000;
+
+// bonuses in centipawns, written the way white sees the board: rank 8 on the first line, a-file on the left
+const PAWN_TABLE: [i32; 64] = [
+     0,  0,  0,  0,  0,  0,  0,  0,
+    50, 50, 50, 50, 50, 50, 50, 50,
+    10, 10, 20, 30, 30, 20, 10, 10,
+     5,  5, 10, 25, 25, 10,  5,  5,
+     0,  0,  0, 20, 20,  0,  0,  0,
+     5, -5,-10,  0,  0,-10, -5,  5,
+     5, 10, 10,-20,-20, 10, 10,  5,
+     0,  0,  0,  0,  0,  0,  0,  0,
+];
+const KNIGHT_TABLE: [i32; 64] = [
+    -50,-40,-30,-30,-30,-30,-40,-50,
+    -40,-20,  0,  0,  0,  0,-20,-40,
+    -30,  0, 10, 15, 15, 10,  0,-30,
+    -30,  5, 15, 20, 20, 15,  5,-30,
+    -30,  0, 15, 20, 20, 15,  0,-30,
+    -30,  5, 10, 15, 15, 10,  5,-30,
+    -40,-20,  0,  5,  5,  0,-20,-40,
+    -50,-40,-30,-30,-30,-30,-40,-50,
+];
+const BISHOP_TABLE: [i32; 64] = [
+    -20,-10,-10,-10,-10,-10,-10,-20,
+    -10,  0,  0,  0,  0,  0,  0,-10,
+    -10,  0,  5, 10, 10,  5,  0,-10,
+    -10,  5,  5, 10, 10,  5,  5,-10,
+    -10,  0, 10, 10, 10, 10,  0,-10,
+    -10, 10, 10, 10, 10, 10, 10,-10,
+    -10,  5,  0,  0,  0,  0,  5,-10,
+    -20,-10,-10,-10,-10,-10,-10,-20,
+];
+const ROOK_TABLE: [i32; 64] = [
+     0,  0,  0,  0,  0,  0,  0,  0,
+     5, 10, 10, 10, 10, 10, 10,  5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+     0,  0,  0,  5,  5,  0,  0,  0,
+];
+const QUEEN_TABLE: [i32; 64] = [
+    -20,-10,-10, -5, -5,-10,-10,-20,
+    -10,  0,  0,  0,  0,  0,  0,-10,
+    -10,  0,  5,  5,  5,  5,  0,-10,
+     -5,  0,  5,  5,  5,  5,  0, -5,
+      0,  0,  5,  5,  5,  5,  0, -5,
+    -10,  5,  5,  5,  5,  5,  0,-10,
+    -10,  0,  5,  0,  0,  0,  0,-10,
+    -20,-10,-10, -5, -5,-10,-10,-20,
+];
+const KING_TABLE: [i32; 64] = [
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -20,-30,-30,-40,-40,-30,-30,-20,
+    -10,-20,-20,-20,-20,-20,-20,-10,
+     20, 20,  0,  0,  0,  0, 20, 20,
+     20, 30, 10,  0,  0, 10, 30, 20,
+];
+
+fn piece_value(piece: Piece) -> i32 {
+    match piece_to_char(piece) {
+        'p' => 100,
+        'n' => 320,
+        'b' => 330,
+        'r' => 500,
+        'q' => 900,
+        _ => 20_000,
+    }
+}
+
+fn square_bonus(piece: Piece, square: u8) -> i32 {
+    let (row, file) = ((square / 8) as usize, (square % 8) as usize);
+    let index = if piece.color() == ChessColor::White { (7 - row) * 8 + file } else { row * 8 + file }; // black reads the tables upside down
+    let table = match piece_to_char(piece) {
+        'p' => &PAWN_TABLE,
+        'n' => &KNIGHT_TABLE,
+        'b' => &BISHOP_TABLE,
+        'r' => &ROOK_TABLE,
+        'q' => &QUEEN_TABLE,
+        _ => &KING_TABLE,
+    };
+    table[index]
+}
+
+/// The position's score in centipawns for the side to move, positive when it is ahead.
+pub fn evaluate(game: &Game) -> i32 {
+    let to_move = game.player_tracker();
+    (0..64)
+        .filter_map(|square| get_piece_at(&game.position, square).map(|piece| (square, piece)))
+        .map(|(square, piece)| {
+            let score = piece_value(piece) + square_bonus(piece, square);
+            if piece.color() == to_move { score } else { -score }
+        })
+        .sum()
+}
+
+/// Every move the side to move can make, with the game after it. Captures come first so alpha-beta cuts sooner.
+/// `castling` is the rights of the starting position, as in move_piece::castles_without_right.
+fn legal_moves(game: &Game, castling: Castling) -> Vec<(MoveRecord, Game)> {
+    let mover = game.player_tracker();
+    let mut moves = Vec::new();
+    for from in 0..64 {
+        let Some(piece) = get_piece_at(&game.position, from) else { continue };
+        if piece.color() != mover {
+            continue;
+        }
+        for candidate in valid_moves(from, piece, &game.position) {
+            if move_piece::castles_without_right(game, from, candidate.to, castling) {
+                continue;
+            }
+            let mut after = game.clone();
+            if make_move(candidate, &mut after).is_err() {
+                continue;
+            }
+            let promotion = get_piece_at(&after.position, candidate.to).map(piece_to_char).filter(|&c| c != piece_to_char(piece));
+            let taken = get_piece_at(&game.position, candidate.to).map_or(0, piece_value);
+            moves.push((taken * 10 - piece_value(piece) / 100, MoveRecord { from, to: candidate.to, promotion }, after));
+        }
+    }
+    moves.sort_by_key(|(order, _, _)| -order);
+    moves.into_iter().map(|(_, record, after)| (record, after)).collect()
+}
+
+fn negamax(game: &Game, castling: Castling, depth: u32, mut alpha: i32, beta: i32, ply: i32, stop: &AtomicBool) -> i32 {
+    if stop.load(Ordering::Relaxed) { // the answer won't be used, get out quickly
+        return 0;
+    }
+    match game.result {
+        GameResult::Checkmate(_) => return -MATE + ply, // the side to move is mated, sooner is worse
+        GameResult::Stalemate => return 0,
+        GameResult::Ongoing => {}
+    }
+    if depth == 0 {
+        return evaluate(game);
+    }
+    let moves = legal_moves(game, castling);
+    if moves.is_empty() { // in case the crate didn't mark the game as over
+        let mover = game.player_tracker();
+        let opponent = if mover == ChessColor::White { ChessColor::Black } else { ChessColor::White };
+        return if notation::gives_check(game, opponent) { -MATE + ply } else { 0 };
+    }
+    for (_, after) in moves {
+        let score = -negamax(&after, castling, depth - 1, -beta, -alpha, ply + 1, stop);
+        if score >= beta {
+            return beta;
+        }
+        alpha = alpha.max(score);
+    }
+    alpha
+}
+
+/// The move the engine likes best after looking `depth` half moves ahead and its score, None when there is nothing to play.
+/// Setting `stop` cuts the search short, what it returns then is meaningless.
+fn search(game: &Game, castling: Castling, depth: u32, stop: &AtomicBool) -> Option<(MoveRecord, i32)> {
+    let mut best = None;
+    let mut alpha = -MATE - 1;
+    for (record, after) in legal_moves(game, castling) {
+        let score = -negamax(&after, castling, depth.max(1) - 1, -MATE - 1, -alpha, 1, stop);
+        if best.is_none() || score > alpha {
+            alpha = score;
+            best = Some(record);
+        }
+    }
+    best.map(|record| (record, alpha))
+}
+
+pub fn best_move(game: &Game, castling: Castling, depth: u32) -> Option<MoveRecord> {
+    search(game, castling, depth, &AtomicBool::new(false)).map(|(record, _)| record)
+}
+
+fn to_score(score: i32) -> Score {
+    if score > MATE - 1000 {
+        Score::Mate((MATE - score + 1) / 2)
+    }
+    else if score < -MATE + 1000 {
+        Score::Mate(-(MATE + score) / 2)
+    }
+    else {
+        Score::Centipawns(score)
+    }
+}
+
+/// Searches one half move deeper at a time up to `max_depth`, sending what it found after each depth.
+/// Stops as soon as the returned Worker is dropped, even in the middle of a depth.
+pub fn analyse(game: Game, castling: Castling, max_depth: u32) -> Worker<Analysis> {
+    Worker::start(move |tx, stop| {
+        for depth in 1..=max_depth {
+            let found = search(&game, castling, depth, &stop);
+            if stop.load(Ordering::Relaxed) { // nobody is looking at this position anymore
+                break;
+            }
+            let analysis = Analysis { best: found.map(|(record, _)| record), score: found.map(|(_, score)| to_score(score)), depth };
+            if tx.send(analysis).is_err() || found.is_none() { // or the game is over
+                break;
+            }
+        }
+    })
+}
+
+/// Searches `depth` half moves ahead without blocking the gui, the chosen move (None once the game is over) arrives on the returned channel.
+pub fn think(game: Game, castling: Castling, depth: u32) -> Receiver<Option<MoveRecord>> {
+    let (tx, rx) = channel::bounded(1);
+    thread::spawn(move || {
+        let _ = tx.send(best_move(&game, castling, depth)); // the gui may have stopped listening, that's fine
+    });
+    rx
+}
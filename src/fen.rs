@@ -0,0 +1,248 @@
+// fen.rs reads and writes positions in FEN (Forsyth-Edwards Notation), e.g. "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1"
+
+use std::fmt;
+
+use chess::*;
+use chess::piece::{Color as ChessColor, Piece};
+use chess::position::get_piece_at;
+
+use crate::helper::piece_to_char;
+use crate::move_piece;
+use crate::notation;
+use crate::protocol::MoveRecord;
+
+pub const START_FEN: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+const MAX_COUNTER: u32 = 100_000; // far beyond any real game, keeps the turn count from overflowing
+
+pub struct Fen {
+    pub board: [Option<Piece>; 64], // 0 is a1, 63 is h8
+    pub to_move: ChessColor,
+    pub castling: Castling,
+    pub en_passant: Option<u8>, // the square a pawn skipped over with its double step
+    pub halfmove: u32, // half moves since the last capture or pawn move
+    pub fullmove: u32, // starts at 1, goes up after black moves
+}
+
+impl Fen {
+    pub fn parse(text: &str) -> Result<Fen, String> {
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        if fields.len() < 2 || fields.len() > 6 { // the counters are often left out
+            return Err(format!("expected 2 to 6 fields, got {}", fields.len()));
+        }
+
+        let ranks: Vec<&str> = fields[0].split('/').collect();
+        if ranks.len() != 8 {
+            return Err(format!("expected 8 ranks, got {}", ranks.len()));
+        }
+        let mut board = [None; 64];
+        for (i, rank) in ranks.iter().enumerate() {
+            let row = 7 - i as u8; // the first rank written is the eighth
+            let mut file = 0u8;
+            for c in rank.chars() {
+                if let Some(empty) = c.to_digit(10) {
+                    if !(1..=8).contains(&empty) {
+                        return Err(format!("'{}' can't stand for empty squares", c));
+                    }
+                    file = file.checked_add(empty as u8).filter(|&file| file <= 8).ok_or_else(|| format!("rank {} has more than 8 squares", row + 1))?;
+                    continue;
+                }
+                if file >= 8 {
+                    return Err(format!("rank {} has more than 8 squares", row + 1));
+                }
+                board[(row * 8 + file) as usize] = Some(char_to_piece(c).ok_or_else(|| format!("unknown piece '{}'", c))?);
+                file += 1;
+            }
+            if file != 8 {
+                return Err(format!("rank {} does not have 8 squares", row + 1));
+            }
+        }
+        for color in [ChessColor::White, ChessColor::Black] {
+            let kings = board.iter().flatten().filter(|piece| matches!(piece, Piece::King(c) if *c == color)).count();
+            if kings != 1 {
+                return Err(format!("{:?} needs exactly one king, found {}", color, kings));
+            }
+        }
+
+        let to_move = match fields[1] {
+            "w" => ChessColor::White,
+            "b" => ChessColor::Black,
+            other => return Err(format!("side to move must be w or b, got '{}'", other)),
+        };
+        let castling = Castling::parse(fields.get(2).copied().unwrap_or("-"))?;
+        let en_passant = match fields.get(3).copied().unwrap_or("-") {
+            "-" => None,
+            square => Some(notation::square_index(square).ok_or_else(|| format!("bad en passant square '{}'", square))?),
+        };
+        let halfmove: u32 = fields.get(4).map_or(Ok(0), |n| n.parse()).map_err(|_| "bad halfmove clock".to_string())?;
+        let fullmove: u32 = fields.get(5).map_or(Ok(1), |n| n.parse()).map_err(|_| "bad fullmove number".to_string())?;
+        if halfmove > MAX_COUNTER || fullmove > MAX_COUNTER {
+            return Err(format!("move counters above {} are not supported", MAX_COUNTER));
+        }
+
+        Ok(Fen { board, to_move, castling, en_passant, halfmove, fullmove: fullmove.max(1) })
+    }
+
+    /// The FEN of the position reached by playing `history` from `start`. The chess crate doesn't expose
+    /// castling rights, en passant or the halfmove clock, so they are worked out from the moves.
+    pub fn after_moves(start: &Fen, history: &[MoveRecord]) -> Fen {
+        let mut game = start.to_game();
+        let mut castling = start.castling;
+        let mut en_passant = start.en_passant; // kept when there are no moves yet
+        let mut halfmove = start.halfmove;
+        for record in history {
+            let mover = get_piece_at(&game.position, record.from);
+            let is_pawn = mover.map(piece_to_char) == Some('p');
+            let captures = get_piece_at(&game.position, record.to).is_some();
+            if !move_piece::play_quietly(&mut game, *record, start.castling) {
+                break; // can't happen for moves that made it onto the board
+            }
+            for (square, lost) in [(4, "KQ"), (7, "K"), (0, "Q"), (60, "kq"), (63, "k"), (56, "q")] { // a king or rook leaving or being taken
+                if record.from == square || record.to == square {
+                    castling.remove(lost);
+                }
+            }
+            en_passant = if is_pawn && record.from.abs_diff(record.to) == 16 { Some((record.from + record.to) / 2) } else { None };
+            halfmove = if is_pawn || captures { 0 } else { halfmove + 1 };
+        }
+        let mut board = [None; 64];
+        for (square, piece) in board.iter_mut().enumerate() {
+            *piece = get_piece_at(&game.position, square as u8);
+        }
+        Fen {
+            board,
+            to_move: game.player_tracker(),
+            castling,
+            en_passant,
+            halfmove,
+            fullmove: (((game.turn as usize).max(1) - 1) / 2 + 1) as u32,
+        }
+    }
+
+    /// How many half moves came before this position, 0 for white's first move.
+    pub fn start_ply(&self) -> usize {
+        (self.fullmove as usize - 1) * 2 + if self.to_move == ChessColor::Black { 1 } else { 0 }
+    }
+
+    /// A game set up at this position. The chess crate keeps its own castling and en passant state and has no way
+    /// to set it, so moves check `castling` themselves (see move_piece::castles_without_right).
+    pub fn to_game(&self) -> Game {
+        let mut position = initialize_board();
+        for (square, piece) in self.board.iter().enumerate() {
+            position::set_piece_at(&mut position, square as u8, *piece);
+        }
+        let mut game = Game::new(position);
+        game.turn = (self.start_ply() + 1).try_into().expect("parse keeps the fullmove number small"); // white moves on odd turns
+        game
+    }
+}
+
+impl fmt::Display for Fen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for row in (0..8).rev() {
+            let mut empty = 0;
+            for file in 0..8 {
+                match self.board[row * 8 + file] {
+                    Some(piece) => {
+                        if empty > 0 {
+                            write!(f, "{}", empty)?;
+                            empty = 0;
+                        }
+                        let letter = piece_to_char(piece);
+                        write!(f, "{}", if piece.color() == ChessColor::White { letter.to_ascii_uppercase() } else { letter })?;
+                    }
+                    None => empty += 1,
+                }
+            }
+            if empty > 0 {
+                write!(f, "{}", empty)?;
+            }
+            if row > 0 {
+                write!(f, "/")?;
+            }
+        }
+        let en_passant = self.en_passant.map_or("-".to_string(), index_to_square);
+        write!(
+            f,
+            " {} {} {} {} {}",
+            if self.to_move == ChessColor::White { "w" } else { "b" },
+            self.castling,
+            en_passant,
+            self.halfmove,
+            self.fullmove
+        )
+    }
+}
+
+fn char_to_piece(c: char) -> Option<Piece> { // uppercase is white
+    let color = if c.is_ascii_uppercase() { ChessColor::White } else { ChessColor::Black };
+    match c.to_ascii_lowercase() {
+        'p' => Some(Piece::Pawn(color)),
+        'n' => Some(Piece::Knight(color)),
+        'b' => Some(Piece::Bishop(color)),
+        'r' => Some(Piece::Rook(color)),
+        'q' => Some(Piece::Queen(color)),
+        'k' => Some(Piece::King(color)),
+        _ => None,
+    }
+}
+
+/// The castling rights a game starting from `fen` has, all four for the normal start.
+pub fn castling_rights(fen: Option<&str>) -> Castling {
+    match fen.map(Fen::parse) {
+        Some(Ok(fen)) => fen.castling,
+        _ => Castling::ALL,
+    }
+}
+
+/// Which of "KQkq" a position still allows, one bit per letter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Castling(u8);
+
+impl Castling {
+    pub const ALL: Castling = Castling(0b1111);
+
+    pub fn parse(text: &str) -> Result<Castling, String> { // "KQkq", a subset of it, or "-"
+        if text == "-" {
+            return Ok(Castling(0));
+        }
+        text.chars().try_fold(Castling(0), |rights, right| match bit(right) {
+            Some(bit) => Ok(Castling(rights.0 | bit)),
+            None => Err(format!("bad castling rights '{}'", text)),
+        })
+    }
+
+    pub fn allows(self, right: char) -> bool {
+        bit(right).is_some_and(|bit| self.0 & bit != 0)
+    }
+
+    pub fn remove(&mut self, rights: &str) {
+        for right in rights.chars() {
+            self.0 &= !bit(right).unwrap_or(0);
+        }
+    }
+}
+
+impl fmt::Display for Castling {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "-");
+        }
+        "KQkq".chars().filter(|&right| self.allows(right)).try_for_each(|right| write!(f, "{}", right))
+    }
+}
+
+fn bit(right: char) -> Option<u8> {
+    "KQkq".find(right).map(|i| 1 << i)
+}
+
+/// The game a FEN describes, or the normal starting position when there is none.
+pub fn start_game(fen: Option<&str>) -> Game {
+    match fen.map(Fen::parse) {
+        Some(Ok(fen)) => fen.to_game(),
+        Some(Err(e)) => {
+            eprintln!("Ignoring bad FEN, starting from the normal position: {}", e);
+            Game::new(initialize_board())
+        }
+        None => Game::new(initialize_board()),
+    }
+}
@@ -1,17 +1,8 @@
-use std::sync::mpsc::{self, Sender, Receiver};
-use std::net::{TcpStream, TcpListener};
-use std::thread;
-use std::io::{Read, Write};
-
-use ggez::ContextBuilder;
-use ggez::event;
-
-use crate::MblomstGui;
-use crate::ChessColor;
-use crate::piece::Piece;
-use crate::piece::Color;
-use crate::helper as chess_helper;
-use crate::ggezGameResult;
+use chess::piece::Color as ChessColor;
+use chess::piece::Piece;
+use chess::piece::Color;
+use chess::Game;
+use chess::position::get_piece_at;
 
 pub fn piece_to_code(piece: Piece) -> String { // translates pieces into their "code". 
     match piece {
@@ -30,3 +21,39 @@ pub fn piece_to_code(piece: Piece) -> String { // translates pieces into their "
         Piece::King(Color::Black)   => "bK".to_string(),
     }
 }
+
+pub fn piece_to_char(piece: Piece) -> char { // translates pieces into the lowercase letter used for promotions
+    match piece {
+        Piece::Pawn(_)   => 'p',
+        Piece::Knight(_) => 'n',
+        Piece::Bishop(_) => 'b',
+        Piece::Rook(_)   => 'r',
+        Piece::Queen(_)  => 'q',
+        Piece::King(_)   => 'k',
+    }
+}
+
+/// A fingerprint of the pieces on the board and the side to move. Uses FNV-1a rather than std's hasher
+/// so that both players get the same number even if their builds differ.
+pub fn position_hash(game: &Game) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut feed = |byte: u8| {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    };
+    for square in 0..64 {
+        match get_piece_at(&game.position, square) {
+            Some(piece) => piece_to_code(piece).bytes().for_each(&mut feed),
+            None => feed(b'.'),
+        }
+    }
+    feed(if game.player_tracker() == ChessColor::White { b'w' } else { b'b' });
+    hash
+}
+
+pub fn color_name(color: ChessColor) -> &'static str { // "White" or "Black", as used in the win message names
+    match color {
+        ChessColor::White => "White",
+        ChessColor::Black => "Black",
+    }
+}
@@ -1,5 +1,26 @@
 // enter in terminal for host: cargo run -- --host <5 number port>
 // enter in terminal for client: cargo run -- --connect 127.0.0.1:<same 5 number port>
+// optional for both: --name <your name> --color white|black|random (the host gets its color if both ask for the same)
+//                   --time <minutes>+<increment seconds>, e.g. --time 5+3 (the host's time control wins)
+// keys: G resigns, D offers a draw, R asks for a rematch once the game is over, Y/N accepts or declines the opponent's offer,
+// F flips the board (it already starts with your own color at the bottom), S saves the game as PGN in games/ (also done when it ends)
+// Enter (or a click on the chat box) starts typing a chat message, Enter sends it and Escape cancels, the mouse wheel scrolls the chat
+// to watch a saved game: cargo run -- --replay <file.pgn>, then Left/Right steps, Home/End (or Up/Down) jumps to the start/end
+// and clicking a move in the move list jumps to it
+// --fen "<fen>" starts from a custom position (the host's wins), V pastes a FEN from the clipboard before the first move
+// and C copies the FEN of the board shown to the clipboard (and prints it)
+// two players on one machine: cargo run -- --local, add --auto-flip to turn the board towards the side to move after each move
+// against the computer: cargo run -- --ai white|black (the color it plays) --level 1-5 (how far it looks ahead, 2 if left out)
+// add --engine <path to a UCI engine> to play against that instead, with --movetime <ms> (1000 if left out) or --depth <half moves>
+// headless bridge for a UCI chess gui or engine harness: cargo run -- --uci-bridge --host <port> (or --connect <addr>),
+// --color picks the harness's color, the person at the other end plays the other one
+// to watch someone's game: cargo run -- --spectate <host's address>, the host's panel shows how many are watching
+// through a relay (started with cargo run --bin mblomst-relay -- <port>): cargo run -- --relay <address> --create <game name>,
+// the other player uses --join <game name> instead, and --relay <address> --list prints the games waiting for a player
+// on a local network the host announces its game: cargo run -- --browse lists the games heard of and a click joins one
+// (a game that already has two players is watched instead)
+// A turns analysis on and off (offline, in replays or once a network game is over): an evaluation bar and an arrow for the best move,
+// from the --engine if one was given, otherwise from the built-in one
 
 use chess::position::get_piece_at;
 use chess::*;
@@ -12,19 +33,116 @@ use ggez::event::{self, EventHandler};
 use ggez::graphics::{self, Color, Canvas};
 use ggez::input::mouse::MouseButton;
 use ggez::graphics::Image;
-use ggez::input::keyboard::KeyCode;
+use ggez::input::keyboard::{KeyCode, KeyInput};
 
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::collections::HashMap;
 use std::env;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use crossbeam::channel::Receiver;
 
+use clock::{Clocks, TimeControl};
 use connection_state::ConnectionState;
+use protocol::{ColorChoice, GameView, Incoming, Message, MoveRecord, Side};
+use replay::Replay;
+use uci::UciEngine;
+use analysis::Analysis;
+use fen::{Castling, Fen};
+use worker::Worker;
 
 mod connection_state;
 mod connection;
 mod move_piece;
 mod helper;
+mod protocol;
+mod clock;
+mod notation;
+mod pgn;
+mod replay;
+mod fen;
+mod engine;
+mod uci;
+mod analysis;
+mod bridge;
+mod discovery;
+mod worker;
+
+const PANEL_WIDTH: f32 = 220.0; // room right of the board for buttons and game info
+const MOVE_LINE_HEIGHT: f32 = 22.0;
+const EVAL_BAR_WIDTH: f32 = 8.0;
+const CHAT_MAX_LEN: usize = 200;
+const ANALYSIS_DEPTH: u32 = 4; // for the built-in engine, the UCI one uses the --movetime or --depth limit
+const FOUND_TIMEOUT: Duration = Duration::from_secs(7); // a host that stopped announcing for this long is taken off the list
+const FLAG_GRACE_MS: i64 = 2000; // how far past zero the opponent's clock may go before we call it, covers network lag
+
+#[derive(Clone, Copy, PartialEq)]
+enum PanelButton { // the buttons in the side panel, top to bottom
+    Resign,
+    OfferDraw,
+    Rematch,
+    SavePgn,
+    First, // the replay buttons
+    Back,
+    Forward,
+    Last,
+}
+
+impl PanelButton {
+    const NETWORK: [PanelButton; 4] = [PanelButton::Resign, PanelButton::OfferDraw, PanelButton::Rematch, PanelButton::SavePgn];
+    const REPLAY: [PanelButton; 4] = [PanelButton::First, PanelButton::Back, PanelButton::Forward, PanelButton::Last];
+
+    fn label(self) -> &'static str {
+        match self {
+            PanelButton::Resign => "Resign (G)",
+            PanelButton::OfferDraw => "Offer draw (D)",
+            PanelButton::Rematch => "Rematch (R)",
+            PanelButton::SavePgn => "Save PGN (S)",
+            PanelButton::First => "Start (Home)",
+            PanelButton::Back => "Back (Left)",
+            PanelButton::Forward => "Forward (Right)",
+            PanelButton::Last => "End (End)",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Offer { // something one player proposes and the other has to answer
+    Draw,
+    Rematch,
+}
+
+impl Offer {
+    fn message(self) -> Message {
+        match self {
+            Offer::Draw => Message::DrawOffer,
+            Offer::Rematch => Message::RematchOffer,
+        }
+    }
+
+    fn answer(self, accept: bool) -> Message {
+        match (self, accept) {
+            (Offer::Draw, true) => Message::DrawAccept,
+            (Offer::Draw, false) => Message::DrawDecline,
+            (Offer::Rematch, true) => Message::RematchAccept,
+            (Offer::Rematch, false) => Message::RematchDecline,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Offer::Draw => "draw",
+            Offer::Rematch => "rematch",
+        }
+    }
+}
+
+struct PendingPromotion { // a pawn move waiting for the player to pick what it promotes to
+    from: u8,
+    to: u8,
+    choices: Vec<char>,
+}
 
 struct MblomstGui {
     game: Game,
@@ -38,12 +156,50 @@ struct MblomstGui {
     win_messages: HashMap<String, Image>,
     stalemate: bool,
     connection_state: Arc<Mutex<ConnectionState>>,
+    pending_promotion: Option<PendingPromotion>,
+    notice: Option<String>,
+    history: Vec<MoveRecord>, // every move that made it onto the board, used to resync with the host
+    san_history: Vec<String>, // the same moves in algebraic notation, for the move list
+    desync: bool,
+    flagged: bool, // set when a move was refused by either side, the board stays locked until a resync
+    resigned: Option<String>, // the color that gave up
+    timed_out: Option<String>, // the color whose clock ran out
+    clocks: Option<Clocks>, // None when playing without a time control
+    draw_agreed: bool,
+    offer_sent: Option<Offer>, // we proposed something and wait for an answer
+    offer_received: Option<Offer>, // the opponent proposed something and waits for our answer
+    manual_flip: bool, // toggled with F, on top of the automatic orientation
+    flipped: bool, // black at the bottom, worked out every update from our color and manual_flip
+    started_at: (i64, u32, u32, u32, u32, u32), // when the game began, for the PGN date and file name
+    pgn_saved: bool, // the finished game has been written to disk
+    replay: Option<Replay>, // watching a saved game instead of playing
+    start_fen: Option<String>, // the position the game started from, None is the normal start
+    start_castling: Castling, // what start_fen allows, read once instead of on every move
+    start_ply: usize, // half moves that came before the starting position, for numbering the moves
+    clipboard: Option<arboard::Clipboard>, // kept open, on some systems copied text disappears with it
+    local: bool, // both colors are played from this window, nothing goes over the network
+    auto_flip: bool, // in local play, the side to move sits at the bottom
+    ai_color: Option<ChessColor>, // the color the built-in engine plays, if any
+    ai_depth: u32,
+    ai_thinking: Option<(usize, Receiver<Option<MoveRecord>>)>, // how many moves were played when it started, and where its answer arrives
+    uci_engine: Option<Arc<Mutex<UciEngine>>>, // plays instead of the built-in engine when given
+    uci_limit: uci::Limit,
+    analysing: bool,
+    analysis: Option<(Analysis, Option<String>)>, // the latest result for the board shown, with its best move in algebraic notation
+    analysis_rx: Option<(u64, Worker<Analysis>)>, // the position hash being analysed and the search, dropping it stops the search
+    chat: Vec<(String, String)>, // who said what, oldest first
+    chat_input: Option<String>, // Some while typing, the keyboard shortcuts are off then
+    chat_scroll: usize, // how many of the newest messages are scrolled out of view
+    watching: Option<(String, String)>, // the white and black player's names when spectating
+    browser: Option<Worker<discovery::Host>>, // Some while picking a game on the local network, dropping it stops listening
+    found: Vec<(discovery::Host, Instant)>, // the hosts heard from and when they last announced themselves
 }
 
 impl MblomstGui {
     pub fn new(ctx: &mut Context, connection_state: Arc<Mutex<ConnectionState>>) -> ggezGameResult<MblomstGui> {
-        let position = initialize_board(); // initiate game and board
-        let game = Game::new(position);
+        let start_fen = connection_state.lock().unwrap().start_fen.clone();
+        let game = fen::start_game(start_fen.as_deref()); // initiate game and board
+        let start_ply = game.turn as usize - 1;
 
         let mut piece_images = HashMap::new(); // connects pieces to their coresponding image
         let piece_codes = ["wP", "wN", "wB", "wR", "wQ", "wK", "bP", "bN", "bB", "bR", "bQ", "bK"];
@@ -60,7 +216,7 @@ impl MblomstGui {
         }
 
         let mut win_messages = HashMap::new(); // connects "win messages" to their coresponding images
-        let messages_name = ["White_won", "Black_won", "Stalemate"];
+        let messages_name = ["White_won", "Black_won", "Stalemate", "White_resigned", "Black_resigned", "Draw_agreed", "White_out_of_time", "Black_out_of_time"];
         for message in messages_name {
             let path = format!("/messages/{}.png", message);
             match Image::from_path(ctx, path) {
@@ -85,16 +241,929 @@ impl MblomstGui {
             win_messages,
             stalemate: false,
             connection_state,
+            pending_promotion: None,
+            notice: None,
+            history: Vec::new(),
+            san_history: Vec::new(),
+            desync: false,
+            flagged: false,
+            resigned: None,
+            timed_out: None,
+            clocks: None,
+            draw_agreed: false,
+            offer_sent: None,
+            offer_received: None,
+            manual_flip: false,
+            flipped: false,
+            started_at: pgn::now(),
+            pgn_saved: false,
+            replay: None,
+            start_castling: fen::castling_rights(start_fen.as_deref()),
+            start_fen,
+            start_ply,
+            clipboard: None,
+            local: false,
+            auto_flip: false,
+            ai_color: None,
+            ai_depth: 2,
+            ai_thinking: None,
+            uci_engine: None,
+            uci_limit: uci::Limit::MoveTime(1000),
+            analysing: false,
+            analysis: None,
+            analysis_rx: None,
+            chat: Vec::new(),
+            chat_input: None,
+            chat_scroll: 0,
+            watching: None,
+            browser: None,
+            found: Vec::new(),
         })
     }
 
     fn screen_to_square(&self, x: f32, y: f32) -> Option<String> { // selects a square from coordinates
+        if x < 0.0 || y < 0.0 || x >= self.board_size.0 || y >= self.board_size.1 {
+            return None;
+        }
         let col = (x / self.square_x) as usize;
         let row = (y / self.square_y) as usize;
-        let file = (b'a' + col as u8) as char;
-        let rank = 8 - row;
+        let (file, rank) = if self.flipped { (7 - col, row + 1) } else { (col, 8 - row) };
+        let file = (b'a' + file as u8) as char;
         Some(format!("{}{}", file, rank))
     }
+
+    fn square_origin(&self, square_index: u8) -> (f32, f32) { // top left corner of a square on screen, the opposite of screen_to_square
+        let col = (square_index % 8) as f32;
+        let row = (square_index / 8) as f32;
+        if self.flipped {
+            ((7.0 - col) * self.square_x, row * self.square_y)
+        }
+        else {
+            (col * self.square_x, (7.0 - row) * self.square_y)
+        }
+    }
+
+    fn promotion_rect(&self, i: usize) -> graphics::Rect { // the square of the i:th piece in the promotion chooser, centered on the board
+        let (board_size_x, board_size_y) = self.board_size;
+        let x = board_size_x / 2.0 - 2.0 * self.square_x + i as f32 * self.square_x;
+        let y = board_size_y / 2.0 - self.square_y / 2.0;
+        graphics::Rect::new(x, y, self.square_x, self.square_y)
+    }
+
+    fn draw_banner(&self, ctx: &mut Context, canvas: &mut Canvas, message: &str, y: f32) -> ggezGameResult { // draws a line of text on a dark strip across the board
+        let mut text = graphics::Text::new(message);
+        text.set_scale(self.square_y * 0.3);
+        let banner = graphics::Mesh::new_rectangle(
+            ctx,
+            graphics::DrawMode::fill(),
+            graphics::Rect::new(0.0, y, self.board_size.0, self.square_y * 0.5),
+            Color::from_rgba(20, 20, 20, 200),
+        )?;
+        canvas.draw(&banner, graphics::DrawParam::default());
+        canvas.draw(&text, graphics::DrawParam::default().dest([self.square_x * 0.1, y + self.square_y * 0.1]).color(Color::WHITE));
+        Ok(())
+    }
+
+    fn game_finished(&self) -> bool { // over on the board, or ended by agreement or resignation
+        self.game.is_over() || self.resigned.is_some() || self.timed_out.is_some() || self.draw_agreed
+    }
+
+    fn result_code(&self) -> Option<String> { // name of the win message to show, if the game has ended
+        if self.checkmate {
+            Some(format!("{}_won", self.color_won.clone().unwrap_or_default()))
+        }
+        else if let Some(color) = &self.resigned {
+            Some(format!("{}_resigned", color))
+        }
+        else if let Some(color) = &self.timed_out {
+            Some(format!("{}_out_of_time", color))
+        }
+        else if self.stalemate {
+            Some("Stalemate".to_string())
+        }
+        else if self.draw_agreed {
+            Some("Draw_agreed".to_string())
+        }
+        else {
+            None
+        }
+    }
+
+    fn buttons(&self) -> &'static [PanelButton] {
+        if self.replay.is_some() { &PanelButton::REPLAY } else { &PanelButton::NETWORK }
+    }
+
+    fn press(&mut self, button: PanelButton) {
+        match button {
+            PanelButton::Resign => self.resign(),
+            PanelButton::OfferDraw => self.make_offer(Offer::Draw),
+            PanelButton::Rematch => self.make_offer(Offer::Rematch),
+            PanelButton::SavePgn => self.save_pgn(),
+            PanelButton::First => self.replay_to(0),
+            PanelButton::Back => self.replay_step(false),
+            PanelButton::Forward => self.replay_step(true),
+            PanelButton::Last => self.replay_to(usize::MAX),
+        }
+    }
+
+    fn start_replay(&mut self, replay: Replay) {
+        let white = replay.pgn.tag("White").unwrap_or("?").to_string();
+        let black = replay.pgn.tag("Black").unwrap_or("?").to_string();
+        self.notice = Some(format!("{} vs {}, {}", white, black, replay.pgn.result));
+        self.replay = Some(replay);
+        self.pgn_saved = true; // it came from a file already
+        self.show_replay_position();
+    }
+
+    fn replay_to(&mut self, ply: usize) {
+        if let Some(replay) = &mut self.replay {
+            replay.go_to(ply);
+        }
+        self.show_replay_position();
+    }
+
+    fn replay_step(&mut self, forward: bool) {
+        if let Some(replay) = &mut self.replay {
+            replay.step(forward);
+        }
+        self.show_replay_position();
+    }
+
+    fn show_replay_position(&mut self) { // puts the replay's current position on the board, update works out the result again
+        let Some(replay) = &self.replay else { return };
+        self.game = replay.game().clone();
+        self.history = replay.records[..replay.ply].to_vec();
+        self.checkmate = false;
+        self.stalemate = false;
+        self.color_won = None;
+        self.selected_square = None;
+        self.pending_promotion = None;
+    }
+
+    fn move_list(&self) -> &[String] { // the moves shown in the side panel
+        match &self.replay {
+            Some(replay) => &replay.san,
+            None => &self.san_history,
+        }
+    }
+
+    fn move_list_top(&self) -> f32 { // under the buttons, clocks and analysis line
+        let mut top = self.button_rect(self.buttons().len()).y;
+        if self.clocks.is_some() {
+            top += 90.0;
+        }
+        if self.analysing {
+            top += 26.0;
+        }
+        top
+    }
+
+    fn move_list_start(&self) -> usize { // half moves played before the first move in the list, when starting from a FEN
+        match &self.replay {
+            Some(replay) => replay.start_ply,
+            None => self.start_ply,
+        }
+    }
+
+    fn move_row(&self, index: usize) -> usize { // the line of the move list the index:th move is on
+        (index + self.move_list_start()) / 2 - self.move_list_start() / 2
+    }
+
+    fn move_list_bottom(&self) -> f32 { // the chat sits under the move list in network games
+        if self.chat_enabled() { self.chat_top() - 6.0 } else { self.board_size.1 - 10.0 }
+    }
+
+    fn chat_enabled(&self) -> bool {
+        !self.local && self.replay.is_none() && self.browser.is_none()
+    }
+
+    fn start_browsing(&mut self) {
+        match discovery::listen() {
+            Ok(browser) => {
+                self.browser = Some(browser);
+                self.notice = Some("Looking for games on the local network, click one to join".to_string());
+            }
+            Err(e) => {
+                println!("Failed to look for games: {}", e);
+                self.notice = Some(format!("Can't look for games: {}", e));
+            }
+        }
+    }
+
+    fn update_browsing(&mut self) { // keeps the list of hosts up to date, one entry per address
+        let Some(browser) = &self.browser else { return };
+        while let Ok(host) = browser.rx.try_recv() {
+            self.found.retain(|(known, _)| known.address != host.address);
+            self.found.push((host, Instant::now()));
+        }
+        self.found.retain(|(_, heard)| heard.elapsed() < FOUND_TIMEOUT);
+        self.found.sort_by_key(|(host, _)| host.address); // so the list doesn't reshuffle with every announcement
+    }
+
+    fn found_rect(&self, i: usize) -> graphics::Rect { // the i:th game found, listed down the board
+        graphics::Rect::new(self.square_x * 0.5, self.square_y * (0.8 + i as f32 * 0.7), self.board_size.0 - self.square_x, self.square_y * 0.6)
+    }
+
+    fn join_found(&mut self, i: usize) {
+        let Some((host, _)) = self.found.get(i) else { return };
+        let address = host.address.to_string();
+        let watch = host.announcement.playing;
+        println!("{} {} at {}", if watch { "Watching" } else { "Joining" }, host.announcement.name, address);
+        {
+            let mut state = self.connection_state.lock().unwrap();
+            state.is_host = false;
+            state.spectator = watch;
+        }
+        self.browser = None;
+        self.found.clear();
+        self.notice = None;
+        let conn_clone = Arc::clone(&self.connection_state);
+        thread::spawn(move || {
+            connection::start_client(&address, conn_clone);
+        });
+    }
+
+    fn can_chat(&self) -> bool { // spectators read along but don't write
+        self.chat_enabled() && !self.spectating()
+    }
+
+    fn spectating(&self) -> bool {
+        self.connection_state.lock().unwrap().spectator
+    }
+
+    fn spectator_count(&self) -> Option<usize> { // only the host knows, and only the host shows it
+        let state = self.connection_state.lock().unwrap();
+        (state.is_host && !self.local).then_some(state.spectators.len())
+    }
+
+    fn tell_spectators(&self, msg: Message) {
+        let mut state = self.connection_state.lock().unwrap();
+        if state.is_host {
+            state.tell_spectators(msg);
+        }
+    }
+
+    fn update_spectators(&mut self) { // the host hands the game to its spectators again whenever something in it changes
+        if self.local || self.replay.is_some() || !self.connection_state.lock().unwrap().is_host {
+            return;
+        }
+        let (white, black) = self.player_names();
+        let view = GameView {
+            white,
+            black,
+            time_control: self.clocks.as_ref().map(|clocks| clocks.time_control),
+            fen: self.start_fen.clone(),
+            moves: self.history.clone(),
+            result: self.result_code(),
+        };
+        let clocks = self.clocks.as_ref().map(|clocks| (clocks.remaining(ChessColor::White).max(0) as u64, clocks.remaining(ChessColor::Black).max(0) as u64));
+        let mut state = self.connection_state.lock().unwrap();
+        if state.watched.as_ref().is_none_or(|(watched, _)| *watched != view) {
+            state.tell_spectators(Message::Spectate { view: view.clone(), clocks });
+        }
+        state.watched = Some((view, clocks)); // the clocks keep up for whoever joins next
+    }
+
+    fn watch(&mut self, view: GameView, clocks: Option<(u64, u64)>) { // a spectator's board follows the host's
+        if view.fen != self.start_fen || !view.moves.starts_with(&self.history) { // a rematch, or a board we can't catch up from
+            self.set_start(view.fen.clone());
+            self.started_at = pgn::now();
+            self.pgn_saved = false;
+        }
+        for &record in &view.moves[self.history.len()..] {
+            if let Err(e) = self.apply_move(record) {
+                println!("Could not follow the host's move {:?}: {}", record, e);
+                self.notice = Some("The host's moves don't apply on this board".to_string());
+                break;
+            }
+        }
+        let ended = view.result.as_deref().unwrap_or_default(); // checkmate and stalemate are seen on the board
+        self.resigned = ended.strip_suffix("_resigned").map(str::to_string);
+        self.timed_out = ended.strip_suffix("_out_of_time").map(str::to_string);
+        self.draw_agreed = ended == "Draw_agreed";
+        self.color_won = match self.resigned.as_deref().or(self.timed_out.as_deref()) {
+            Some("White") => Some("Black".to_string()),
+            Some(_) => Some("White".to_string()),
+            None => None,
+        };
+        self.clocks = match (view.time_control, clocks) {
+            (Some(time_control), Some((white_ms, black_ms))) => {
+                let mut shown = self.clocks.take().unwrap_or_else(|| Clocks::new(time_control));
+                shown.set(ChessColor::White, white_ms);
+                shown.set(ChessColor::Black, black_ms);
+                Some(shown)
+            }
+            _ => None,
+        };
+        if self.watching.is_none() {
+            self.notice = Some(format!("Watching {} vs {}", view.white, view.black));
+        }
+        self.watching = Some((view.white, view.black));
+    }
+
+    fn chat_top(&self) -> f32 { // the chat gets the lower part of the side panel
+        self.board_size.1 * 0.6
+    }
+
+    fn chat_input_rect(&self) -> graphics::Rect {
+        graphics::Rect::new(self.board_size.0 + 10.0, self.board_size.1 - 36.0, PANEL_WIDTH - 20.0, 28.0)
+    }
+
+    fn send_chat(&mut self) {
+        let Some(text) = self.chat_input.take() else { return };
+        let text = text.trim().to_string();
+        if text.is_empty() {
+            return;
+        }
+        self.send(Message::Chat { text: text.clone(), from: None });
+        let name = self.connection_state.lock().unwrap().name.clone();
+        self.tell_spectators(Message::Chat { text: text.clone(), from: Some(name) });
+        self.chat.push(("You".to_string(), text));
+        self.chat_scroll = 0;
+    }
+
+    fn draw_chat(&self, ctx: &mut Context, canvas: &mut Canvas) -> ggezGameResult { // the scrollback, newest at the bottom, and the input box under it
+        let left = self.board_size.0 + 10.0;
+        let width = PANEL_WIDTH - 20.0;
+        let divider = graphics::Mesh::new_rectangle(ctx, graphics::DrawMode::fill(), graphics::Rect::new(left, self.chat_top(), width, 2.0), Color::from_rgb(105, 47, 15))?;
+        canvas.draw(&divider, graphics::DrawParam::default());
+
+        let mut top = self.chat_top() + 4.0;
+        if let Some(count) = self.spectator_count() {
+            let mut line = graphics::Text::new(format!("Spectators: {}", count));
+            line.set_scale(16.0);
+            canvas.draw(&line, graphics::DrawParam::default().dest([left + 2.0, top + 2.0]).color(Color::from_rgb(120, 120, 120)));
+            top += 22.0;
+        }
+
+        let mut bottom = self.chat_input_rect().y - 4.0;
+        for (who, text) in self.chat.iter().rev().skip(self.chat_scroll) {
+            let mut line = graphics::Text::new(format!("{}: {}", who, text));
+            line.set_scale(16.0);
+            line.set_bounds([width - 4.0, f32::INFINITY]);
+            line.set_wrap(true);
+            let height = line.measure(ctx)?.y;
+            if bottom - height < top { // older messages are a scroll away
+                break;
+            }
+            bottom -= height + 2.0;
+            canvas.draw(&line, graphics::DrawParam::default().dest([left + 2.0, bottom]).color(Color::BLACK));
+        }
+
+        let rect = self.chat_input_rect();
+        let background = graphics::Mesh::new_rectangle(
+            ctx,
+            graphics::DrawMode::fill(),
+            rect,
+            if self.chat_input.is_some() { Color::WHITE } else { Color::from_rgb(255, 228, 196) },
+        )?;
+        canvas.draw(&background, graphics::DrawParam::default());
+        let (label, color) = match &self.chat_input {
+            Some(typed) => (format!("{}_", typed), Color::BLACK),
+            None if !self.can_chat() => ("Spectators can't chat".to_string(), Color::from_rgb(120, 120, 120)),
+            None => ("Press Enter to chat".to_string(), Color::from_rgb(120, 120, 120)),
+        };
+        let mut text = graphics::Text::new(label);
+        text.set_scale(16.0);
+        text.set_bounds([rect.w - 8.0, rect.h]);
+        canvas.draw(&text, graphics::DrawParam::default().dest([rect.x + 4.0, rect.y + 6.0]).color(color));
+        Ok(())
+    }
+
+    fn move_list_window(&self) -> (usize, usize) { // the first line shown and how many lines fit
+        let lines = match self.move_list().len() {
+            0 => 0,
+            len => self.move_row(len - 1) + 1,
+        };
+        let fits = ((self.move_list_bottom() - self.move_list_top()) / MOVE_LINE_HEIGHT).max(0.0) as usize;
+        let first = match &self.replay {
+            Some(replay) => self.move_row(replay.ply.saturating_sub(1)).saturating_sub(fits / 2).min(lines.saturating_sub(fits)), // keeps the current move in view
+            None => lines.saturating_sub(fits), // the latest moves
+        };
+        (first, fits.min(lines - first))
+    }
+
+    fn move_rect(&self, index: usize) -> Option<graphics::Rect> { // where the index:th move is in the move list, None if scrolled away
+        let (first, fits) = self.move_list_window();
+        let row = self.move_row(index);
+        if index >= self.move_list().len() || row < first || row >= first + fits {
+            return None;
+        }
+        Some(graphics::Rect::new(
+            self.board_size.0 + 50.0 + ((index + self.move_list_start()) % 2) as f32 * 80.0,
+            self.move_list_top() + (row - first) as f32 * MOVE_LINE_HEIGHT,
+            78.0,
+            MOVE_LINE_HEIGHT,
+        ))
+    }
+
+    fn button_rect(&self, i: usize) -> graphics::Rect { // the i:th button in the side panel
+        graphics::Rect::new(self.board_size.0 + 10.0, 10.0 + i as f32 * 50.0, PANEL_WIDTH - 20.0, 40.0)
+    }
+
+    fn prompt_button_rect(&self, i: usize) -> graphics::Rect { // accept (0) and decline (1) under the offer prompt
+        let (board_size_x, board_size_y) = self.board_size;
+        graphics::Rect::new(board_size_x / 2.0 - 130.0 + i as f32 * 140.0, board_size_y / 2.0 + 10.0, 120.0, 40.0)
+    }
+
+    fn my_color(&self) -> Option<ChessColor> {
+        self.connection_state.lock().unwrap().my_color
+    }
+
+    fn start_local(&mut self, auto_flip: bool) {
+        self.local = true;
+        self.auto_flip = auto_flip;
+        if let Some(time_control) = self.connection_state.lock().unwrap().time_control {
+            self.clocks = Some(Clocks::new(time_control));
+        }
+    }
+
+    fn start_ai(&mut self, color: ChessColor, depth: u32) { // a local game where one side is played by the engine
+        self.start_local(false);
+        self.ai_color = Some(color);
+        self.ai_depth = depth;
+    }
+
+    fn update_ai(&mut self) { // starts the engine on its turn and plays its move once it has one
+        let Some(ai_color) = self.ai_color else { return };
+        if let Some((played, rx)) = &self.ai_thinking {
+            let Ok(answer) = rx.try_recv() else { return }; // still thinking
+            let stale = *played != self.history.len() || self.game_finished() || self.game.player_tracker() != ai_color; // the board changed under it
+            self.ai_thinking = None;
+            match answer {
+                Some(record) if !stale => self.play_move(record.from, record.to, record.promotion),
+                Some(_) => {}
+                None => println!("The engine found no move"),
+            }
+        }
+        else if !self.game_finished() && self.game.player_tracker() == ai_color {
+            let answer = match &self.uci_engine {
+                Some(uci_engine) => uci::think(Arc::clone(uci_engine), self.start_fen.clone(), self.history.clone(), self.uci_limit),
+                None => engine::think(self.game.clone(), self.castling_rights(), self.ai_depth),
+            };
+            self.ai_thinking = Some((self.history.len(), answer));
+        }
+    }
+
+    fn toggle_analysis(&mut self) {
+        let network_game = !self.local && self.replay.is_none();
+        if !self.analysing && network_game && !self.game_finished() { // no engine help against a person
+            self.notice = Some("Analysis is only available offline or once the game is over".to_string());
+            return;
+        }
+        self.analysing = !self.analysing;
+        self.analysis = None;
+        self.analysis_rx = None;
+    }
+
+    fn update_analysis(&mut self) { // starts over whenever the board changes and collects what the engine finds
+        if !self.analysing {
+            return;
+        }
+        let key = helper::position_hash(&self.game);
+        if self.analysis_rx.as_ref().map(|(analysed, _)| *analysed) != Some(key) {
+            let rx = match &self.uci_engine {
+                Some(uci_engine) => uci::analyse(Arc::clone(uci_engine), self.shown_start_fen().map(String::from), self.history.clone(), self.uci_limit),
+                None => engine::analyse(self.game.clone(), self.castling_rights(), ANALYSIS_DEPTH),
+            };
+            self.analysis = None;
+            self.analysis_rx = Some((key, rx));
+        }
+        let Some((_, running)) = &self.analysis_rx else { return };
+        let mut latest = None;
+        while let Ok(analysis) = running.rx.try_recv() {
+            latest = Some(analysis);
+        }
+        if let Some(analysis) = latest {
+            let san = analysis.best.and_then(|best| {
+                let mut after = self.game.clone();
+                move_piece::play_quietly(&mut after, best, self.castling_rights()).then(|| notation::to_san(&self.game, &after, best))
+            });
+            self.analysis = Some((analysis, san));
+        }
+    }
+
+    fn square_center(&self, square: u8) -> [f32; 2] {
+        let (x, y) = self.square_origin(square);
+        [x + self.square_x / 2.0, y + self.square_y / 2.0]
+    }
+
+    fn draw_arrow(&self, ctx: &mut Context, canvas: &mut Canvas, from: u8, to: u8) -> ggezGameResult { // a green arrow between two squares
+        let (start, end) = (self.square_center(from), self.square_center(to));
+        let (dx, dy) = (end[0] - start[0], end[1] - start[1]);
+        let length = (dx * dx + dy * dy).sqrt();
+        if length < 1.0 {
+            return Ok(());
+        }
+        let (ux, uy) = (dx / length, dy / length);
+        let head = self.square_x.min(self.square_y) * 0.35;
+        let neck = [end[0] - ux * head, end[1] - uy * head];
+        let color = Color::from_rgba(30, 150, 30, 180);
+        let shaft = graphics::Mesh::new_line(ctx, &[start, neck], self.square_x.min(self.square_y) * 0.12, color)?;
+        canvas.draw(&shaft, graphics::DrawParam::default());
+        let tip = graphics::Mesh::new_polygon(
+            ctx,
+            graphics::DrawMode::fill(),
+            &[end, [neck[0] - uy * head * 0.6, neck[1] + ux * head * 0.6], [neck[0] + uy * head * 0.6, neck[1] - ux * head * 0.6]],
+            color,
+        )?;
+        canvas.draw(&tip, graphics::DrawParam::default());
+        Ok(())
+    }
+
+    fn resign(&mut self) {
+        let color = match self.ai_color {
+            Some(ChessColor::White) => Some(ChessColor::Black), // only the human resigns
+            Some(ChessColor::Black) => Some(ChessColor::White),
+            None if self.local => Some(self.game.player_tracker()), // at a shared board the side to move gives up
+            None => self.my_color(),
+        };
+        let Some(color) = color else { return };
+        if self.game_finished() {
+            return;
+        }
+        self.send(Message::Resign);
+        self.resigned = Some(helper::color_name(color).to_string());
+        self.color_won = Some(helper::color_name(if color == ChessColor::White { ChessColor::Black } else { ChessColor::White }).to_string());
+    }
+
+    fn out_of_time(&mut self, color: ChessColor) { // flag fall, the other color wins
+        let winner = if color == ChessColor::White { ChessColor::Black } else { ChessColor::White };
+        self.timed_out = Some(helper::color_name(color).to_string());
+        self.color_won = Some(helper::color_name(winner).to_string());
+    }
+
+    fn make_offer(&mut self, offer: Offer) {
+        let allowed = match offer { // draws are offered during the game, rematches after it
+            Offer::Draw => !self.game_finished(),
+            Offer::Rematch => self.game_finished(),
+        };
+        if self.local { // nobody to ask, both players are at the board
+            let engine_agrees = match (offer, self.ai_color) { // the engine takes a draw when it thinks it is worse off
+                (Offer::Draw, Some(ai_color)) => {
+                    let score = engine::evaluate(&self.game);
+                    (if self.game.player_tracker() == ai_color { score } else { -score }) < 0
+                }
+                _ => true,
+            };
+            if allowed && engine_agrees {
+                self.offer_accepted(offer);
+            }
+            else if allowed {
+                self.notice = Some("The computer declined the draw".to_string());
+            }
+            return;
+        }
+        if self.my_color().is_none() || !allowed || self.offer_sent.is_some() {
+            return;
+        }
+        self.send(offer.message());
+        self.offer_sent = Some(offer);
+        self.notice = Some(format!("Offered a {}, waiting for the opponent", offer.name()));
+    }
+
+    fn answer_offer(&mut self, accept: bool) {
+        let Some(offer) = self.offer_received.take() else { return };
+        self.send(offer.answer(accept));
+        if accept {
+            self.offer_accepted(offer);
+        }
+    }
+
+    fn offer_accepted(&mut self, offer: Offer) { // both players run this, one when answering and one when hearing the answer
+        self.offer_sent = None;
+        self.notice = None;
+        match offer {
+            Offer::Draw => self.draw_agreed = true,
+            Offer::Rematch => self.start_rematch(),
+        }
+    }
+
+    fn start_rematch(&mut self) { // a fresh board with the colors swapped, done in the same order on both sides
+        let my_color = {
+            let mut state = self.connection_state.lock().unwrap();
+            state.my_color = state.my_color.map(|color| if color == ChessColor::White { ChessColor::Black } else { ChessColor::White });
+            state.turn = 1;
+            state.my_color
+        };
+        self.game = fen::start_game(self.start_fen.as_deref());
+        self.history.clear();
+        self.san_history.clear();
+        self.selected_square = None;
+        self.pending_promotion = None;
+        self.checkmate = false;
+        self.stalemate = false;
+        self.color_won = None;
+        self.resigned = None;
+        self.timed_out = None;
+        if let Some(clocks) = &mut self.clocks {
+            *clocks = Clocks::new(clocks.time_control);
+        }
+        self.draw_agreed = false;
+        self.desync = false;
+        self.flagged = false;
+        self.started_at = pgn::now();
+        self.pgn_saved = false;
+        if let Some(color) = my_color {
+            self.notice = Some(format!("Rematch! You play {}", helper::color_name(color)));
+        }
+    }
+
+    fn set_start(&mut self, start_fen: Option<String>) { // a fresh board at a new starting position, only used before the first move
+        self.game = fen::start_game(start_fen.as_deref());
+        self.start_ply = self.game.turn as usize - 1;
+        self.start_castling = fen::castling_rights(start_fen.as_deref());
+        self.start_fen = start_fen;
+        self.history.clear();
+        self.san_history.clear();
+        self.selected_square = None;
+        self.pending_promotion = None;
+        self.checkmate = false;
+        self.stalemate = false;
+        self.color_won = None;
+    }
+
+    fn paste_fen(&mut self) { // sets up the position on the clipboard, the host tells the client about it
+        if !self.history.is_empty() {
+            self.notice = Some("The starting position can only change before the first move".to_string());
+            return;
+        }
+        let (connected, is_host) = {
+            let state = self.connection_state.lock().unwrap();
+            (state.connected, state.is_host)
+        };
+        if connected && !is_host {
+            self.notice = Some("Only the host picks the starting position".to_string());
+            return;
+        }
+        let text = match self.clipboard().and_then(|clipboard| clipboard.get_text().map_err(|e| e.to_string())) {
+            Ok(text) => text.trim().to_string(),
+            Err(e) => {
+                println!("Failed to read the clipboard: {}", e);
+                self.notice = Some(format!("Could not read the clipboard: {}", e));
+                return;
+            }
+        };
+        if let Err(e) = Fen::parse(&text) {
+            self.notice = Some(format!("Not a FEN: {}", e));
+            return;
+        }
+        println!("Starting from {}", text);
+        self.connection_state.lock().unwrap().start_fen = Some(text.clone()); // used in the handshake from now on
+        self.set_start(Some(text.clone()));
+        if connected {
+            self.send(Message::StartPosition { fen: Some(text) });
+        }
+        self.notice = Some("Set up the position from the clipboard".to_string());
+    }
+
+    fn clipboard(&mut self) -> Result<&mut arboard::Clipboard, String> {
+        if self.clipboard.is_none() {
+            self.clipboard = Some(arboard::Clipboard::new().map_err(|e| e.to_string())?);
+        }
+        Ok(self.clipboard.as_mut().unwrap())
+    }
+
+    fn shown_start_fen(&self) -> Option<&str> { // where the game on screen started, in a replay too
+        match &self.replay {
+            Some(replay) => replay.pgn.tag("FEN"),
+            None => self.start_fen.as_deref(),
+        }
+    }
+
+    fn castling_rights(&self) -> Castling { // of the position the game on screen started from
+        match &self.replay {
+            Some(replay) => replay.castling,
+            None => self.start_castling,
+        }
+    }
+
+    fn current_fen(&self) -> String { // FEN of the board on screen
+        let start = Fen::parse(self.shown_start_fen().unwrap_or(fen::START_FEN)).unwrap_or_else(|_| Fen::parse(fen::START_FEN).unwrap());
+        Fen::after_moves(&start, &self.history).to_string()
+    }
+
+    fn copy_fen(&mut self) {
+        let text = self.current_fen();
+        println!("FEN: {}", text);
+        match self.clipboard().and_then(|clipboard| clipboard.set_text(text.clone()).map_err(|e| e.to_string())) {
+            Ok(()) => self.notice = Some("Copied the FEN to the clipboard".to_string()),
+            Err(e) => {
+                println!("Failed to write the clipboard: {}", e);
+                self.notice = Some(format!("Could not copy the FEN: {}", e));
+            }
+        }
+    }
+
+    fn pgn_result(&self) -> &'static str { // the result the way PGN writes it
+        if !self.game_finished() {
+            "*"
+        }
+        else if self.color_won.as_deref() == Some("White") {
+            "1-0"
+        }
+        else if self.color_won.as_deref() == Some("Black") {
+            "0-1"
+        }
+        else {
+            "1/2-1/2"
+        }
+    }
+
+    fn pgn_path(&self) -> PathBuf { // one file per game, saving again overwrites it
+        let (white, black) = self.player_names();
+        let (year, month, day, hour, minute, second) = self.started_at;
+        PathBuf::from("games").join(format!(
+            "{}-{:02}-{:02}_{:02}{:02}{:02}_{}_vs_{}.pgn",
+            year, month, day, hour, minute, second, pgn::file_safe(&white), pgn::file_safe(&black)
+        ))
+    }
+
+    fn player_names(&self) -> (String, String) { // (white, black)
+        if let Some(names) = &self.watching {
+            return names.clone();
+        }
+        let state = self.connection_state.lock().unwrap();
+        let opponent = state.opponent_name.clone().unwrap_or_else(|| "?".to_string());
+        if self.local {
+            ("White".to_string(), "Black".to_string())
+        }
+        else if state.my_color == Some(ChessColor::Black) {
+            (opponent, state.name.clone())
+        }
+        else {
+            (state.name.clone(), opponent)
+        }
+    }
+
+    fn save_pgn(&mut self) {
+        let (white, black) = self.player_names();
+        let (is_host, time_control) = {
+            let state = self.connection_state.lock().unwrap();
+            (state.is_host, state.time_control)
+        };
+        let (year, month, day, _, _, _) = self.started_at;
+        let termination = if !self.game_finished() {
+            "unterminated"
+        }
+        else if self.timed_out.is_some() {
+            "time forfeit"
+        }
+        else {
+            "normal"
+        };
+        let mut game = pgn::PgnGame {
+            tags: vec![
+                ("Event".to_string(), if self.local { "Mblomst local game" } else { "Mblomst network game" }.to_string()),
+                ("Site".to_string(), "LAN".to_string()),
+                ("Date".to_string(), format!("{}.{:02}.{:02}", year, month, day)),
+                ("Round".to_string(), "-".to_string()),
+                ("White".to_string(), white),
+                ("Black".to_string(), black),
+                ("Result".to_string(), self.pgn_result().to_string()),
+                ("TimeControl".to_string(), pgn::time_control_tag(time_control)),
+                ("Termination".to_string(), termination.to_string()),
+                ("NetworkRole".to_string(), if self.local { "local" } else if is_host { "host" } else if self.watching.is_some() { "spectator" } else { "client" }.to_string()),
+            ],
+            moves: self.san_history.clone(),
+            result: self.pgn_result().to_string(),
+        };
+        if let Some(fen) = &self.start_fen { // other programs need both tags to start from the same position
+            game.tags.push(("SetUp".to_string(), "1".to_string()));
+            game.tags.push(("FEN".to_string(), fen.clone()));
+        }
+        let path = self.pgn_path();
+        match game.save(&path) {
+            Ok(()) => {
+                println!("Saved the game to {}", path.display());
+                self.notice = Some(format!("Saved the game to {}", path.display()));
+            }
+            Err(e) => {
+                println!("Failed to save the game to {}: {}", path.display(), e);
+                self.notice = Some(format!("Could not save the game: {}", e));
+            }
+        }
+    }
+
+    fn apply_move(&mut self, record: MoveRecord) -> Result<(), String> { // makes a move on the local board and remembers it
+        let before = self.game.clone();
+        let castling = self.castling_rights();
+        move_piece::execute_move(&mut self.game, record.from, record.to, record.promotion, castling)?;
+        self.history.push(record);
+        self.san_history.push(notation::to_san(&before, &self.game, record));
+        Ok(())
+    }
+
+    fn draw_button(&self, ctx: &mut Context, canvas: &mut Canvas, rect: graphics::Rect, label: &str) -> ggezGameResult { // a light box with a label
+        let background = graphics::Mesh::new_rectangle(
+            ctx,
+            graphics::DrawMode::fill(),
+            rect,
+            Color::from_rgb(255, 228, 196),
+        )?;
+        canvas.draw(&background, graphics::DrawParam::default());
+        let mut text = graphics::Text::new(label);
+        text.set_scale(18.0);
+        canvas.draw(&text, graphics::DrawParam::default().dest([rect.x + 10.0, rect.y + 11.0]).color(Color::BLACK));
+        Ok(())
+    }
+
+    fn play_move(&mut self, from: u8, to: u8, promotion: Option<char>) { // makes a move on the local board and sends it to the second player
+        let mover = self.game.player_tracker();
+        if let Err(e) = self.apply_move(MoveRecord { from, to, promotion }) {
+            println!("{}", e);
+            return; // nothing happened on our board, so nothing to tell the second player
+        }
+        let clock = self.clocks.as_mut().map(|clocks| clocks.finish_move(mover));
+        let msg = Message::Move { from, to, promotion, hash: helper::position_hash(&self.game), clock };
+        self.send(msg);
+    }
+
+    fn send(&self, msg: Message) {
+        if self.local || self.spectating() { // a spectator's link only goes one way
+            return;
+        }
+        let tx = self.connection_state.lock().unwrap().outgoing_tx.clone();
+        if let Err(e) = tx.send(msg) { // sends message to second player
+            println!("Failed to send message: {}", e);
+        }
+    }
+
+    fn receive_move(&mut self, record: MoveRecord, hash: u64, clock: Option<u64>) { // checks and plays a move made by the second player
+        if self.flagged {
+            println!("Ignoring {:?}, the game is flagged", record);
+            return;
+        }
+        let my_color = self.connection_state.lock().unwrap().my_color;
+        if my_color.is_none() || my_color == Some(self.game.player_tracker()) {
+            self.reject_move(record, "it is not your turn".to_string());
+            return;
+        }
+        let mover = self.game.player_tracker();
+        if let Err(e) = self.apply_move(record) { // executes move, will have the same effect as the move just made by the second player
+            self.reject_move(record, e);
+            return;
+        }
+        if let Some(clocks) = &mut self.clocks {
+            match clock {
+                Some(remaining_ms) => clocks.set(mover, remaining_ms), // their clock is the one that counts for their time
+                None => {
+                    clocks.finish_move(mover);
+                }
+            }
+        }
+        if helper::position_hash(&self.game) != hash { // our board no longer matches the one the move was made on
+            println!("Position hash mismatch after {:?}", record);
+            self.desync = true;
+            self.notice = Some("Boards out of sync with the opponent! Press Y to resync from the host".to_string());
+        }
+    }
+
+    fn reject_move(&mut self, record: MoveRecord, reason: String) { // tells the second player we won't play their move and locks the board
+        println!("Rejected opponent move {:?}: {}", record, reason);
+        self.send(Message::IllegalMove { from: record.from, to: record.to, reason: reason.clone() });
+        self.flagged = true;
+        self.notice = Some(format!("Illegal move from the opponent ({}), press Y to resync", reason));
+    }
+
+    fn resume(&mut self, moves: Vec<MoveRecord>) { // catches up with the second player's move list after a reconnect
+        if moves.len() > self.history.len() && moves.starts_with(&self.history) { // they got further than us, play the missing moves
+            let start = self.history.len();
+            for &record in &moves[start..] {
+                if let Err(e) = self.apply_move(record) {
+                    println!("Could not resume with {:?}: {}", record, e);
+                    self.flagged = true;
+                    self.notice = Some("Could not resume the game, press Y to resync".to_string());
+                    return;
+                }
+            }
+            self.notice = Some("Reconnected, caught up with the opponent's moves".to_string());
+        }
+        else if !self.history.starts_with(&moves) { // the lists disagree, the host's list wins
+            if !self.connection_state.lock().unwrap().is_host {
+                self.resync(moves);
+            }
+        }
+        // otherwise we are ahead or equal, and the second player catches up with our list
+    }
+
+    fn resync(&mut self, moves: Vec<MoveRecord>) { // throws away our board and replays the host's moves from the start
+        self.game = fen::start_game(self.start_fen.as_deref());
+        self.history.clear();
+        self.san_history.clear();
+        for record in moves {
+            if let Err(e) = self.apply_move(record) {
+                println!("Resync failed at {:?}: {}", record, e);
+                self.notice = Some("Resync failed, the host's moves don't apply on this board".to_string());
+                return;
+            }
+        }
+        self.selected_square = None;
+        self.pending_promotion = None;
+        self.desync = false;
+        self.flagged = false;
+        self.notice = Some("Board resynced from the host".to_string());
+    }
 }
 
 impl EventHandler for MblomstGui {
@@ -109,27 +1178,175 @@ impl EventHandler for MblomstGui {
 
 
         while let Ok(package) = rx.try_recv() { // try to recive data from second player
-            let parts: Vec<&str> = package.trim().split_whitespace().collect(); // convert type for execute
-            if parts.len() == 2 {
-                if let (Ok(from), Ok(to)) = (parts[0].parse::<u8>(), parts[1].parse::<u8>()) {
-                    move_piece::execute_move(&mut self.game, from, to); // executes move, will have the same effect as the move just made by the second player
+            match package {
+                Incoming::Message(Message::Move { from, to, promotion, hash, clock }) => {
+                    self.receive_move(MoveRecord { from, to, promotion }, hash, clock);
+                }
+                Incoming::Message(Message::Timeout { color }) => {
+                    if !self.game_finished() {
+                        self.out_of_time(color.to_chess());
+                    }
+                }
+                Incoming::Message(Message::IllegalMove { from, to, reason }) => { // our last move never made it onto the opponent's board
+                    println!("Opponent rejected {} {}: {}", from, to, reason);
+                    self.flagged = true;
+                    self.notice = Some(format!("The opponent refused our move ({}), press Y to resync", reason));
+                }
+                Incoming::Connected if self.spectating() => {} // the host sends the game right after the hello
+                Incoming::Message(Message::Spectate { view, clocks }) => {
+                    if self.spectating() {
+                        self.watch(view, clocks);
+                    }
+                }
+                Incoming::Connected => {
+                    let start_fen = self.connection_state.lock().unwrap().start_fen.clone();
+                    if start_fen != self.start_fen {
+                        if self.history.is_empty() {
+                            self.set_start(start_fen);
+                        }
+                        else {
+                            println!("The agreed starting position differs from the one on our board");
+                        }
+                    }
+                    self.send(Message::Resume { moves: self.history.clone() });
+                    let time_control = self.connection_state.lock().unwrap().time_control;
+                    if let (None, Some(time_control)) = (&self.clocks, time_control) { // clocks keep running through a reconnect
+                        self.clocks = Some(Clocks::new(time_control));
+                    }
+                }
+                Incoming::Message(Message::Resume { moves }) => {
+                    self.resume(moves);
+                }
+                Incoming::Message(Message::ResyncRequest) => {
+                    if self.connection_state.lock().unwrap().is_host {
+                        self.send(Message::Resync { moves: self.history.clone() });
+                        self.desync = false;
+                        self.flagged = false;
+                    }
+                }
+                Incoming::Message(Message::Resync { moves }) => {
+                    if !self.connection_state.lock().unwrap().is_host { // the host's board is the one that counts
+                        self.resync(moves);
+                    }
+                }
+                Incoming::Message(Message::Resign) => {
+                    if let Some(color) = self.my_color() {
+                        let opponent = if color == ChessColor::White { ChessColor::Black } else { ChessColor::White };
+                        self.resigned = Some(helper::color_name(opponent).to_string());
+                        self.color_won = Some(helper::color_name(color).to_string());
+                    }
                 }
+                Incoming::Message(Message::DrawOffer) => {
+                    if !self.game_finished() {
+                        self.offer_received = Some(Offer::Draw);
+                    }
+                }
+                Incoming::Message(Message::RematchOffer) => {
+                    if self.game_finished() {
+                        self.offer_received = Some(Offer::Rematch);
+                    }
+                }
+                Incoming::Message(Message::DrawAccept) => {
+                    if self.offer_sent == Some(Offer::Draw) {
+                        self.offer_accepted(Offer::Draw);
+                    }
+                }
+                Incoming::Message(Message::RematchAccept) => {
+                    if self.offer_sent == Some(Offer::Rematch) {
+                        self.offer_accepted(Offer::Rematch);
+                    }
+                }
+                Incoming::Message(Message::DrawDecline) | Incoming::Message(Message::RematchDecline) => {
+                    if let Some(offer) = self.offer_sent.take() {
+                        self.notice = Some(format!("The opponent declined the {}", offer.name()));
+                    }
+                }
+                Incoming::Message(Message::StartPosition { fen }) => {
+                    let is_host = self.connection_state.lock().unwrap().is_host;
+                    if !is_host && self.history.is_empty() && fen.as_deref().is_none_or(|text| Fen::parse(text).is_ok()) {
+                        self.connection_state.lock().unwrap().start_fen = fen.clone();
+                        self.set_start(fen);
+                        self.notice = Some("The host set up a new starting position".to_string());
+                    }
+                }
+                Incoming::Message(Message::Chat { text, from }) => {
+                    let opponent_name = self.connection_state.lock().unwrap().opponent_name.clone();
+                    let name = from.or(opponent_name).unwrap_or_else(|| "Opponent".to_string());
+                    println!("{}: {}", name, text);
+                    self.tell_spectators(Message::Chat { text: text.clone(), from: Some(name.clone()) });
+                    self.chat.push((name, text));
+                    self.chat_scroll = 0;
+                }
+                Incoming::Message(msg) => {
+                    println!("Ignoring message not handled yet: {:?}", msg);
+                }
+                Incoming::Malformed(report) => {
+                    self.notice = Some(report); // shown on top of the board so bad messages don't vanish silently
+                }
+            }
+        }
+
+        match self.game.result { // determines game result, also for moves made by the second player
+            GameResult::Ongoing => {}
+            GameResult::Checkmate(color) => {
+                self.checkmate = true;
+                self.color_won = Some(if color == ChessColor::White { "Black" } else { "White" }.to_string());
+            }
+            GameResult::Stalemate => {
+                self.stalemate = true;
+            }
+        }
+        let (my_color, connected) = {
+            let state = self.connection_state.lock().unwrap();
+            (state.my_color, state.connected)
+        };
+        let to_move = self.game.player_tracker();
+        self.update_ai();
+        self.update_analysis();
+        self.update_spectators();
+        self.update_browsing();
+
+        let running = !self.game_finished() && (connected || self.local) && !self.history.is_empty(); // white's first move is free, and nobody loses time to a dropped link
+        if let Some(clocks) = &mut self.clocks {
+            if running {
+                clocks.tick(to_move, ctx.time.delta());
+            }
+            let remaining = clocks.remaining(to_move);
+            let ours = self.local || my_color == Some(to_move);
+            if running && !self.spectating() && ((ours && remaining <= 0) || (!ours && remaining < -FLAG_GRACE_MS)) { // spectators wait for the host to call it
+                self.send(Message::Timeout { color: Side::from_chess(to_move) });
+                self.out_of_time(to_move);
+            }
+        }
+
+        if self.game_finished() && !self.pgn_saved { // every finished game ends up on disk
+            self.save_pgn();
+            self.pgn_saved = true;
+        }
+
+        if self.game_finished() { // draw offers don't outlive the game
+            if self.offer_sent == Some(Offer::Draw) {
+                self.offer_sent = None;
+            }
+            if self.offer_received == Some(Offer::Draw) {
+                self.offer_received = None;
             }
         }
 
+        let black_at_bottom = if let Some(ai_color) = self.ai_color {
+            ai_color == ChessColor::White // the human's pieces at the bottom
+        } else if self.local {
+            self.auto_flip && self.game.player_tracker() == ChessColor::Black
+        } else {
+            self.my_color() == Some(ChessColor::Black)
+        };
+        self.flipped = black_at_bottom != self.manual_flip; // your own pieces at the bottom unless flipped by hand
+
         let (width, height) = ctx.gfx.drawable_size(); // makes application adjustable to different screen sizes
-        self.board_size = (width, height);
-        self.square_x = width / 8.0;
+        self.board_size = (width - PANEL_WIDTH, height); // the side panel takes the rest
+        self.square_x = self.board_size.0 / 8.0;
         self.square_y = height / 8.0;
 
-        // if ctx.keyboard.is_key_pressed(KeyCode::R) { // only works if you are playing alone
-        //     self.color_won = None;
-        //     self.checkmate = false;
-        //     self.stalemate = false;
-        //     let position = initialize_board();
-        //     self.game = Game::new(position);
-        // }
-
         Ok(())
     }
 
@@ -160,11 +1377,10 @@ impl EventHandler for MblomstGui {
         for row in 0..8 { 
             for col in 0..8 { // goes thrue every "square" on the board 
                 let square_index = row * 8 + col; 
-                if let Some(piece) = position::get_piece_at(&position, square_index as u8) { // to determine what piece should be drawn 
+                if let Some(piece) = position::get_piece_at(position, square_index) { // to determine what piece should be drawn 
                     let code = helper::piece_to_code(piece); // converts the peice to its "name" 
                     if let Some(image) = self.piece_images.get(&code) { // selects the image with the same "name" 
-                        let dest_x = col as f32 * self.square_x; 
-                        let dest_y = (7 - row) as f32 * self.square_y; 
+                        let (dest_x, dest_y) = self.square_origin(square_index); 
                         let image_scale = if self.selected_square.is_some()
                         && chess::square_to_index(&self.selected_square.clone().unwrap()).unwrap() == square_index
                             {
@@ -192,7 +1408,8 @@ impl EventHandler for MblomstGui {
         let possible_moves = if let Some(selected_square) = &self.selected_square { 
             if let Some(from) = chess::square_to_index(selected_square) { // adds all valid moves for the selected "square" if there is one 
                 if let Some(piece) = position::get_piece_at(&self.game.position, from) { 
-                    moves::valid_moves(from, piece, &self.game.position) 
+                    let castling = self.castling_rights(); // no dots for castling the starting position doesn't allow
+                    moves::valid_moves(from, piece, &self.game.position).into_iter().filter(|m| !move_piece::castles_without_right(&self.game, from, m.to, castling)).collect()
                 } 
                 else { 
                     Vec::new() // No piece on square 
@@ -208,10 +1425,7 @@ impl EventHandler for MblomstGui {
         if !possible_moves.is_empty() { // iterates thrue all the valid moves and makes the open spaces dotted while the possible takes are marked with a "scope" 
             for possible_move in possible_moves { 
                 let dest_index = possible_move.to; 
-                let dest_col = dest_index % 8; 
-                let dest_row = dest_index / 8; 
-                let dest_x = dest_col as f32 * self.square_x; 
-                let dest_y = (7 - dest_row) as f32 * self.square_y; 
+                let (dest_x, dest_y) = self.square_origin(dest_index); 
                 if get_piece_at(position, dest_index).is_some() { 
                     let take_space = graphics::Mesh::new_circle( //settings for the "scope" 
                     ctx, 
@@ -235,8 +1449,32 @@ impl EventHandler for MblomstGui {
             } 
         } 
 
-        if self.checkmate { 
-            let code: String = format!("{}_won", self.color_won.clone().unwrap_or_default()); // checks if someone has won 
+        if let Some((Analysis { best: Some(best), .. }, _)) = &self.analysis { // the engine's pick, under the promotion chooser and overlays
+            self.draw_arrow(ctx, &mut canvas, best.from, best.to)?;
+        }
+
+        if let Some(pending) = &self.pending_promotion { // draws the promotion chooser on top of the board
+            let color = if self.game.player_tracker() == ChessColor::White { "w" } else { "b" };
+            for (i, choice) in pending.choices.iter().enumerate() {
+                let rect = self.promotion_rect(i);
+                let background = graphics::Mesh::new_rectangle(
+                    ctx,
+                    graphics::DrawMode::fill(),
+                    rect,
+                    Color::from_rgba(255, 255, 255, 230),
+                )?;
+                canvas.draw(&background, graphics::DrawParam::default());
+                let code = format!("{}{}", color, choice.to_ascii_uppercase());
+                if let Some(image) = self.piece_images.get(&code) {
+                    let param = graphics::DrawParam::default()
+                    .dest([rect.x, rect.y])
+                    .scale([self.square_x / image.width() as f32, self.square_y / image.height() as f32]);
+                    canvas.draw(image, param);
+                }
+            }
+        }
+
+        if let Some(code) = self.result_code() { // shows how the game ended
             if let Some(image) = self.win_messages.get(&code) { 
                 let param = graphics::DrawParam::default() 
                 .dest([0.0, 0.0]) // selects the corner of the board 
@@ -246,21 +1484,209 @@ impl EventHandler for MblomstGui {
                 ]); 
                 canvas.draw(image, param); 
             } 
+            else { // no picture for this ending, write it out instead
+                self.draw_banner(ctx, &mut canvas, &code.replace('_', " "), board_size_y / 2.0 - self.square_y * 0.25)?;
+            }
         } 
-        if self.stalemate { 
-            if let Some(image) = self.win_messages.get("Stalemate") { 
-                let param = graphics::DrawParam::default() 
-                .dest([0.0, 0.0]) // selects the corner of the board 
-                .scale([ board_size_x / image.width() as f32, // selects the entire application screen (is not fully stretched to the sides due to the png's structure) 
-                board_size_y / image.height() as f32, 
-                ]); 
-                canvas.draw(image, param); 
-            } 
-        } 
+
+        if let Some(offer) = self.offer_received { // asks whether to accept the opponent's offer
+            self.draw_banner(ctx, &mut canvas, &format!("The opponent offers a {}", offer.name()), board_size_y / 2.0 - self.square_y * 0.5)?;
+            for (i, label) in ["Accept (Y)", "Decline (N)"].iter().enumerate() {
+                self.draw_button(ctx, &mut canvas, self.prompt_button_rect(i), label)?;
+            }
+        }
+
+        for (i, button) in self.buttons().iter().enumerate() { // the side panel
+            self.draw_button(ctx, &mut canvas, self.button_rect(i), button.label())?;
+        }
+        if let Some(clocks) = &self.clocks { // the clocks under the buttons, the one that is running is highlighted
+            let top = self.button_rect(self.buttons().len()).y;
+            for (i, color) in [ChessColor::White, ChessColor::Black].into_iter().enumerate() {
+                let rect = graphics::Rect::new(self.board_size.0 + 10.0, top + i as f32 * 40.0, PANEL_WIDTH - 20.0, 34.0);
+                let running = color == self.game.player_tracker() && !self.game_finished();
+                let background = graphics::Mesh::new_rectangle(
+                    ctx,
+                    graphics::DrawMode::fill(),
+                    rect,
+                    if running { Color::from_rgb(105, 47, 15) } else { Color::from_rgb(255, 228, 196) },
+                )?;
+                canvas.draw(&background, graphics::DrawParam::default());
+                let mut text = graphics::Text::new(format!("{}  {}", helper::color_name(color), clock::format_clock(clocks.remaining(color))));
+                text.set_scale(22.0);
+                canvas.draw(&text, graphics::DrawParam::default().dest([rect.x + 10.0, rect.y + 6.0]).color(if running { Color::WHITE } else { Color::BLACK }));
+            }
+        }
+
+        if let Some((analysis, san)) = &self.analysis { // the evaluation bar along the board's edge and a line of text over the move list
+            if let Some(score) = analysis.score {
+                let white = score.for_white(self.game.player_tracker() == ChessColor::White);
+                let white_height = board_size_y * white.bar_fraction();
+                let black_bar = graphics::Mesh::new_rectangle(ctx, graphics::DrawMode::fill(), graphics::Rect::new(board_size_x, 0.0, EVAL_BAR_WIDTH, board_size_y), Color::BLACK)?;
+                canvas.draw(&black_bar, graphics::DrawParam::default());
+                let white_top = if self.flipped { 0.0 } else { board_size_y - white_height }; // white's share grows from white's side of the board
+                let white_bar = graphics::Mesh::new_rectangle(ctx, graphics::DrawMode::fill(), graphics::Rect::new(board_size_x, white_top, EVAL_BAR_WIDTH, white_height.max(1.0)), Color::WHITE)?;
+                canvas.draw(&white_bar, graphics::DrawParam::default());
+
+                let mut line = format!("{}  depth {}", white.label(), analysis.depth);
+                if let Some(san) = san {
+                    line = format!("{}  {}", san, line);
+                }
+                let mut text = graphics::Text::new(line);
+                text.set_scale(18.0);
+                canvas.draw(&text, graphics::DrawParam::default().dest([board_size_x + 14.0, self.move_list_top() - 24.0]).color(Color::BLACK));
+            }
+        }
+
+        let current = self.replay.as_ref().and_then(|replay| replay.ply.checked_sub(1)); // the move that led to the board shown
+        let (first, fits) = self.move_list_window();
+        let start = self.move_list_start();
+        for row in first..first + fits { // the move list under the clocks, numbers then the two moves
+            let move_number = start / 2 + row + 1;
+            let mut number = graphics::Text::new(format!("{}.", move_number));
+            number.set_scale(18.0);
+            let y = self.move_list_top() + (row - first) as f32 * MOVE_LINE_HEIGHT;
+            canvas.draw(&number, graphics::DrawParam::default().dest([self.board_size.0 + 14.0, y]).color(Color::BLACK));
+            if row == 0 && start % 2 == 1 { // the position was set up with black to move
+                let mut dots = graphics::Text::new("...");
+                dots.set_scale(18.0);
+                canvas.draw(&dots, graphics::DrawParam::default().dest([self.board_size.0 + 54.0, y + 2.0]).color(Color::BLACK));
+            }
+            for ply in (move_number - 1) * 2..move_number * 2 {
+                let Some(index) = ply.checked_sub(start) else { continue };
+                let Some(rect) = self.move_rect(index) else { continue };
+                let highlighted = current == Some(index);
+                if highlighted {
+                    let background = graphics::Mesh::new_rectangle(ctx, graphics::DrawMode::fill(), rect, Color::from_rgb(105, 47, 15))?;
+                    canvas.draw(&background, graphics::DrawParam::default());
+                }
+                let mut text = graphics::Text::new(self.move_list()[index].as_str());
+                text.set_scale(18.0);
+                canvas.draw(&text, graphics::DrawParam::default().dest([rect.x + 4.0, rect.y + 2.0]).color(if highlighted { Color::WHITE } else { Color::BLACK }));
+            }
+        }
+
+        if self.chat_enabled() {
+            self.draw_chat(ctx, &mut canvas)?;
+        }
+
+        if self.browser.is_some() { // the games heard of on the local network, on top of the empty board
+            for (i, (host, _)) in self.found.iter().enumerate() {
+                let announcement = &host.announcement;
+                let label = match (announcement.playing, announcement.time_control) {
+                    (true, _) => format!("{}  (playing, click to watch)", announcement.name),
+                    (false, Some(time_control)) => format!("{}  {}", announcement.name, time_control),
+                    (false, None) => announcement.name.clone(),
+                };
+                self.draw_button(ctx, &mut canvas, self.found_rect(i), &label)?;
+            }
+            if self.found.is_empty() {
+                self.draw_banner(ctx, &mut canvas, "No games found yet", board_size_y / 2.0 - self.square_y * 0.25)?;
+            }
+        }
+
+        if let Some(notice) = &self.notice { // a line of text across the top of the board for anything the player should know about
+            self.draw_banner(ctx, &mut canvas, notice, 0.0)?;
+        }
+        let status = self.connection_state.lock().unwrap().status.clone();
+        if let Some(status) = status { // connection problems go along the bottom of the board
+            self.draw_banner(ctx, &mut canvas, &status, board_size_y - self.square_y * 0.5)?;
+        }
         canvas.finish(ctx)?; // closes the draw 
         Ok(()) 
     }
 
+    fn text_input_event(&mut self, _ctx: &mut Context, character: char) -> ggezGameResult { // typed characters go to the chat box while it is open
+        if let Some(typed) = &mut self.chat_input && !character.is_control() && typed.chars().count() < CHAT_MAX_LEN {
+            typed.push(character);
+        }
+        Ok(())
+    }
+
+    fn mouse_wheel_event(&mut self, ctx: &mut Context, _x: f32, y: f32) -> ggezGameResult { // scrolls the chat when the mouse is over it
+        let mouse = ctx.mouse.position();
+        if self.chat_enabled() && mouse.x >= self.board_size.0 && mouse.y >= self.chat_top() {
+            if y > 0.0 {
+                self.chat_scroll = (self.chat_scroll + 1).min(self.chat.len().saturating_sub(1));
+            }
+            else if y < 0.0 {
+                self.chat_scroll = self.chat_scroll.saturating_sub(1);
+            }
+        }
+        Ok(())
+    }
+
+    fn key_down_event(&mut self, _ctx: &mut Context, input: KeyInput, _repeated: bool) -> ggezGameResult { // handles keyboard shortcuts
+        if self.chat_input.is_some() { // typing, only the keys that edit the message count
+            match input.keycode {
+                Some(KeyCode::Return) | Some(KeyCode::NumpadEnter) => self.send_chat(),
+                Some(KeyCode::Escape) => self.chat_input = None,
+                Some(KeyCode::Back) => {
+                    if let Some(typed) = &mut self.chat_input {
+                        typed.pop();
+                    }
+                }
+                _ => {}
+            }
+        }
+        else if self.can_chat() && matches!(input.keycode, Some(KeyCode::Return) | Some(KeyCode::NumpadEnter)) {
+            self.chat_input = Some(String::new());
+        }
+        else if self.replay.is_some() { // only stepping through the game while watching a replay
+            match input.keycode {
+                Some(KeyCode::Left) => self.replay_step(false),
+                Some(KeyCode::Right) => self.replay_step(true),
+                Some(KeyCode::Home) | Some(KeyCode::Up) => self.replay_to(0),
+                Some(KeyCode::End) | Some(KeyCode::Down) => self.replay_to(usize::MAX),
+                Some(KeyCode::F) => self.manual_flip = !self.manual_flip,
+                Some(KeyCode::C) => self.copy_fen(),
+                Some(KeyCode::A) => self.toggle_analysis(),
+                _ => {}
+            }
+        }
+        else if self.offer_received.is_some() && matches!(input.keycode, Some(KeyCode::Y) | Some(KeyCode::N)) { // answering an offer comes before anything else
+            self.answer_offer(input.keycode == Some(KeyCode::Y));
+        }
+        else if input.keycode == Some(KeyCode::F) {
+            self.manual_flip = !self.manual_flip;
+        }
+        else if input.keycode == Some(KeyCode::G) {
+            self.resign();
+        }
+        else if input.keycode == Some(KeyCode::D) {
+            self.make_offer(Offer::Draw);
+        }
+        else if input.keycode == Some(KeyCode::R) && self.local { // only works if you are playing alone, starts over at any time
+            self.start_rematch();
+        }
+        else if input.keycode == Some(KeyCode::R) {
+            self.make_offer(Offer::Rematch);
+        }
+        else if input.keycode == Some(KeyCode::S) {
+            self.save_pgn();
+        }
+        else if input.keycode == Some(KeyCode::V) {
+            self.paste_fen();
+        }
+        else if input.keycode == Some(KeyCode::C) {
+            self.copy_fen();
+        }
+        else if input.keycode == Some(KeyCode::A) {
+            self.toggle_analysis();
+        }
+        else if input.keycode == Some(KeyCode::Y) && (self.desync || self.flagged) { // resync, always towards the host's board
+            if self.connection_state.lock().unwrap().is_host {
+                self.send(Message::Resync { moves: self.history.clone() });
+                self.desync = false;
+                self.flagged = false;
+                self.notice = Some("Sent our board to the opponent".to_string());
+            }
+            else {
+                self.send(Message::ResyncRequest);
+            }
+        }
+        Ok(())
+    }
+
     fn mouse_button_down_event( // handles mouse events
         &mut self,
         _ctx: &mut Context,
@@ -268,87 +1694,174 @@ impl EventHandler for MblomstGui {
         x: f32,
         y: f32,
     ) -> ggezGameResult {
-        if button == MouseButton::Left {
-            if !self.game.is_over() {
-                if let Some(square) = self.screen_to_square(x, y) { 
-                    match &self.selected_square {
-                        None => { // if no "square" has been pressed before
-                            let position = &self.game.position;
-                            if let Some(piece) = position::get_piece_at(position, chess::helper::square_to_index(&square).unwrap()) {
-                                if piece.color() == self.game.player_tracker()
-                                    && ((self.game.turn % 2 == 1) == self.connection_state.lock().unwrap().is_host) // makes host and client take turns
-                                {
-                                    self.selected_square = Some(square);
-                                }
+        if button == MouseButton::Left && self.browser.is_some() { // only picking a game until one is joined
+            if let Some(i) = (0..self.found.len()).find(|&i| self.found_rect(i).contains([x, y])) {
+                self.join_found(i);
+            }
+        }
+        else if button == MouseButton::Left {
+            if let Some(clicked) = (0..self.buttons().len()).find(|&i| self.button_rect(i).contains([x, y])) { // side panel buttons
+                self.press(self.buttons()[clicked]);
+            }
+            else if self.replay.is_some() { // a click on a move in the list jumps to it, the board is only for looking
+                if let Some(index) = (0..self.move_list().len()).find(|&i| self.move_rect(i).is_some_and(|rect| rect.contains([x, y]))) {
+                    self.replay_to(index + 1);
+                }
+            }
+            else if self.offer_received.is_some() { // only the prompt's buttons respond while it is open
+                if let Some(i) = (0..2).find(|&i| self.prompt_button_rect(i).contains([x, y])) {
+                    self.answer_offer(i == 0);
+                }
+            }
+            else if self.can_chat() && self.chat_input_rect().contains([x, y]) {
+                self.chat_input.get_or_insert_with(String::new);
+            }
+            else if x >= self.board_size.0 { // somewhere else in the side panel
+            }
+            else if let Some(pending) = self.pending_promotion.take() { // a click while choosing a promotion either picks a piece or cancels the move
+                let choice = (0..pending.choices.len()).find(|&i| self.promotion_rect(i).contains([x, y]));
+                if let Some(i) = choice {
+                    self.play_move(pending.from, pending.to, Some(pending.choices[i]));
+                }
+            }
+            else if !self.game_finished() && !self.flagged && let Some(square) = self.screen_to_square(x, y) {
+                match &self.selected_square {
+                    None => { // if no "square" has been pressed before
+                        let position = &self.game.position;
+                        if let Some(piece) = position::get_piece_at(position, chess::helper::square_to_index(&square).unwrap()) {
+                            let playable = (self.local && self.ai_color != Some(piece.color())) // both colors are ours at a shared board, except the engine's
+                                || (self.connection_state.lock().unwrap().my_color == Some(piece.color()) // only the color agreed on in the handshake can be moved
+                                    && self.connection_state.lock().unwrap().connected); // no moves while the link is down, they would get lost
+                            if piece.color() == self.game.player_tracker() && playable {
+                                self.selected_square = Some(square);
                             }
                         }
-                        Some(from_square) => { // if a "square" already has been pressed
-                            if let (Some(from), Some(to)) = (square_to_index(from_square), square_to_index(&square)) {
-                                move_piece::execute_move(&mut self.game, from, to);
-                                let msg = format!("{} {}", from, to);
-                                let tx = self.connection_state.lock().unwrap().outgoing_tx.clone();
-                                if let Err(e) = tx.send(msg.to_string()) { // sends message to second player
-                                    println!("[Host] Failed to send test message: {}", e);
-                                }
-
-
+                    }
+                    Some(from_square) => { // if a "square" already has been pressed
+                        if let (Some(from), Some(to)) = (square_to_index(from_square), square_to_index(&square)) {
+                            let choices = move_piece::promotion_choices(&self.game, from, to);
+                            if choices.is_empty() {
+                                self.play_move(from, to, None);
+                            }
+                            else { // several moves share the destination, let the player pick the piece
+                                self.pending_promotion = Some(PendingPromotion { from, to, choices });
                             }
-                            self.selected_square = None; // resets selected square
                         }
+                        self.selected_square = None; // resets selected square
                     }
                 }
             }
-
-            match self.game.result { // determines game result
-                GameResult::Ongoing => {}
-                GameResult::Checkmate(color) => {
-                    self.checkmate = true;
-                    self.color_won = Some(if color == ChessColor::White { "Black" } else { "White" }.to_string());
-                }
-                GameResult::Stalemate => {
-                    self.stalemate = true;
-                }
-            }
         }
 
         Ok(())
     }
 }
 
+fn arg_value(args: &[String], flag: &str) -> Option<String> { // the value following `flag` on the command line, if given
+    args.iter().position(|arg| arg == flag).and_then(|i| args.get(i + 1)).cloned()
+}
+
 fn main() -> ggez::GameResult {
     let args: Vec<String> = env::args().collect();
-
-    let (mut ctx, event_loop) = ContextBuilder::new("Chess_gui", "Martin")
-        .window_setup(ggez::conf::WindowSetup::default().title("Chess :)"))
-        .add_resource_path("./resources")
-        .build() // initiates the application
-        .expect("Failed to build ggez context");
+    let bridge = args.get(1).map(String::as_str) == Some("--uci-bridge");
+    let mode_at = if bridge { 2 } else { 1 }; // the bridge takes --host or --connect after its own flag
 
     let conn_state = Arc::new(Mutex::new(ConnectionState::new()));
+    if let Some(name) = arg_value(&args, "--name") {
+        conn_state.lock().unwrap().name = name;
+    }
+    if let Some(text) = arg_value(&args, "--fen") {
+        match Fen::parse(&text) {
+            Ok(_) => conn_state.lock().unwrap().start_fen = Some(text),
+            Err(e) => eprintln!("Ignoring --fen, {}", e),
+        }
+    }
+    if let Some(time) = arg_value(&args, "--time") {
+        match TimeControl::parse(&time) {
+            Some(time_control) => conn_state.lock().unwrap().time_control = Some(time_control),
+            None => eprintln!("Unknown time control '{}', expected something like 5+3", time),
+        }
+    }
+    if let Some(color) = arg_value(&args, "--color") {
+        match ColorChoice::parse(&color) {
+            Some(choice) => conn_state.lock().unwrap().color_choice = choice,
+            None => eprintln!("Unknown color '{}', expected white, black or random", color),
+        }
+    }
 
-    if args.len() > 1 { // determines if the player is a server or a client and assignes a thread
-        match args[1].as_str() {
+    if args.len() > mode_at { // determines if the player is a server or a client and assignes a thread
+        match args[mode_at].as_str() {
             "--host" => {
                 conn_state.lock().unwrap().is_host = true;
-                let port: u16 = args.get(2).expect("Port not specified").parse().unwrap();
+                let port: u16 = args.get(mode_at + 1).expect("Port not specified").parse().unwrap();
                 let conn_clone = Arc::clone(&conn_state);
                 thread::spawn(move || {
                     connection::start_server(port, conn_clone);
                 });
+                let conn_clone = Arc::clone(&conn_state);
+                thread::spawn(move || {
+                    discovery::announce(port, conn_clone);
+                });
             }
             "--connect" => {
                 conn_state.lock().unwrap().is_host = false;
-                let addr = args.get(2).expect("Address not specified").clone();
+                let addr = args.get(mode_at + 1).expect("Address not specified").clone();
+                let conn_clone = Arc::clone(&conn_state);
+                thread::spawn(move || {
+                    connection::start_client(&addr, conn_clone);
+                });
+            }
+            "--spectate" => {
+                let mut state = conn_state.lock().unwrap();
+                state.is_host = false;
+                state.spectator = true;
+                drop(state);
+                let addr = args.get(mode_at + 1).expect("Address not specified").clone();
                 let conn_clone = Arc::clone(&conn_state);
                 thread::spawn(move || {
                     connection::start_client(&addr, conn_clone);
                 });
             }
-            _ => println!("Unknown option: {}", args[1]),
+            "--relay" => {
+                let addr = args.get(mode_at + 1).expect("Relay address not specified").clone();
+                let lobby = match (arg_value(&args, "--create"), arg_value(&args, "--join")) {
+                    (Some(game), _) => Message::CreateGame { game },
+                    (None, Some(game)) => Message::JoinGame { game },
+                    (None, None) if args.iter().any(|arg| arg == "--list") => { // only looking
+                        match connection::list_games(&addr) {
+                            Ok(games) if games.is_empty() => println!("No games are waiting for a player"),
+                            Ok(games) => games.iter().for_each(|game| println!("{}", game)),
+                            Err(e) => eprintln!("Failed to list the games: {}", e),
+                        }
+                        return Ok(());
+                    }
+                    (None, None) => {
+                        eprintln!("--relay needs --create <game name>, --join <game name> or --list");
+                        return Ok(());
+                    }
+                };
+                let conn_clone = Arc::clone(&conn_state);
+                thread::spawn(move || {
+                    connection::start_relayed(&addr, lobby, conn_clone);
+                });
+            }
+            "--replay" | "--local" | "--ai" | "--browse" => {} // set up once the gui exists
+            _ => eprintln!("Unknown option: {}", args[mode_at]),
         }
     }
 
-    let my_game = match MblomstGui::new(&mut ctx, Arc::clone(&conn_state)) {
+    if bridge { // no window, stdin and stdout are the harness's
+        bridge::run(conn_state);
+        return Ok(());
+    }
+
+    let (mut ctx, event_loop) = ContextBuilder::new("Chess_gui", "Martin")
+        .window_setup(ggez::conf::WindowSetup::default().title("Chess :)"))
+        .add_resource_path("./resources")
+        .build() // initiates the application
+        .expect("Failed to build ggez context");
+
+    let mut my_game = match MblomstGui::new(&mut ctx, Arc::clone(&conn_state)) {
         Ok(gui) => { // initializes gui
             gui
         }
@@ -358,5 +1871,41 @@ fn main() -> ggez::GameResult {
         }
     };
 
+    if args.get(1).map(String::as_str) == Some("--browse") {
+        my_game.start_browsing();
+    }
+    if args.get(1).map(String::as_str) == Some("--local") {
+        my_game.start_local(args.iter().any(|arg| arg == "--auto-flip"));
+    }
+    if let Some(path) = arg_value(&args, "--engine") { // used for --ai and for analysis
+        match UciEngine::start(&path) {
+            Ok(uci_engine) => {
+                println!("Using {}", uci_engine.name);
+                my_game.uci_engine = Some(Arc::new(Mutex::new(uci_engine)));
+            }
+            Err(e) => println!("Falling back to the built-in engine, {}", e),
+        }
+        if let Some(depth) = arg_value(&args, "--depth").and_then(|depth| depth.parse().ok()) {
+            my_game.uci_limit = uci::Limit::Depth(depth);
+        }
+        else if let Some(ms) = arg_value(&args, "--movetime").and_then(|ms| ms.parse().ok()) {
+            my_game.uci_limit = uci::Limit::MoveTime(ms);
+        }
+    }
+    if let Some(color) = arg_value(&args, "--ai") {
+        let level = arg_value(&args, "--level").and_then(|level| level.parse::<u32>().ok()).unwrap_or(2);
+        match ColorChoice::parse(&color) {
+            Some(ColorChoice::White) => my_game.start_ai(ChessColor::White, level.clamp(1, 5)),
+            Some(ColorChoice::Black) => my_game.start_ai(ChessColor::Black, level.clamp(1, 5)),
+            _ => println!("Unknown color '{}' for --ai, expected white or black", color),
+        }
+    }
+    if let Some(path) = arg_value(&args, "--replay") {
+        match Replay::load(std::path::Path::new(&path)) {
+            Ok(replay) => my_game.start_replay(replay),
+            Err(e) => println!("Failed to open replay '{}': {}", path, e),
+        }
+    }
+
     event::run(ctx, event_loop, my_game) // calls event loop
 }
\ No newline at end of file
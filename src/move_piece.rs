@@ -1,47 +1,106 @@
 // move_piece.rs is taken souly from https://github.com/INDA25PlusPlus/nhg-chess/blob/main/src/main.rs
 
 use chess::*;
+use chess::piece::Piece;
+
+use crate::fen::Castling;
+use crate::helper::piece_to_char;
+use crate::protocol::MoveRecord;
 
 /// Find index of the move that goes to `to_square`.
-pub fn find_move_to(moves: &Vec<Move>, to_square: u8) -> Option<usize> {
+pub fn find_move_to(moves: &[Move], to_square: u8) -> Option<usize> {
     moves.iter().position(|m| m.to == to_square)
 }
+
+/// Find the move that goes to `to_square`, using `promotion` ('q', 'r', 'b' or 'n') to pick between promotion moves sharing that square.
+pub fn find_move(game: &Game, moves: &[Move], to_square: u8, promotion: Option<char>) -> Option<Move> {
+    let candidates: Vec<Move> = moves.iter().copied().filter(|m| m.to == to_square).collect();
+    match promotion {
+        Some(piece) if candidates.len() > 1 => candidates.into_iter().find(|m| promoted_to(game, *m) == Some(piece)),
+        _ => find_move_to(moves, to_square).map(|idx| moves[idx]),
+    }
+}
+
+/// Letter of the piece standing on the destination after `chosen_move`, found by playing it on a copy of the game.
+pub fn promoted_to(game: &Game, chosen_move: Move) -> Option<char> {
+    let mut trial = game.clone();
+    make_move(chosen_move, &mut trial).ok()?;
+    position::get_piece_at(&trial.position, chosen_move.to).map(piece_to_char)
+}
+
+/// The pieces a pawn can promote to when moving from `from_square` to `to_square`, empty if it is not a promotion.
+pub fn promotion_choices(game: &Game, from_square: u8, to_square: u8) -> Vec<char> {
+    let Some(piece) = position::get_piece_at(&game.position, from_square) else {
+        return Vec::new();
+    };
+    let candidates: Vec<Move> = valid_moves(from_square, piece, &game.position).into_iter().filter(|m| m.to == to_square).collect();
+    if candidates.len() < 2 {
+        return Vec::new();
+    }
+    candidates.into_iter().filter_map(|m| promoted_to(game, m)).collect()
+}
+
+/// Whether moving from `from_square` to `to_square` castles on a side that `castling` (the rights of the FEN the game
+/// started from) doesn't allow. The chess crate starts every game with all four rights, so games set up
+/// from a FEN need this on top of valid_moves. Rights that are lost later on are the crate's business again.
+pub fn castles_without_right(game: &Game, from_square: u8, to_square: u8, castling: Castling) -> bool {
+    if !matches!(position::get_piece_at(&game.position, from_square), Some(Piece::King(_))) {
+        return false;
+    }
+    let right = match (from_square, to_square) {
+        (4, 6) => 'K',
+        (4, 2) => 'Q',
+        (60, 62) => 'k',
+        (60, 58) => 'q',
+        _ => return false,
+    };
+    !castling.allows(right)
+}
+
+/// Plays `record` like execute_move does, without the printing. Returns whether it worked.
+pub fn play_quietly(game: &mut Game, record: MoveRecord, castling: Castling) -> bool {
+    if castles_without_right(game, record.from, record.to, castling) {
+        return false;
+    }
+    let Some(piece) = position::get_piece_at(&game.position, record.from) else { return false };
+    let moves = valid_moves(record.from, piece, &game.position);
+    match find_move(game, &moves, record.to, record.promotion) {
+        Some(chosen_move) => make_move(chosen_move, game).is_ok(),
+        None => false,
+    }
+}
+
 /// Execute the move from `from_square` to `to_square` (searches the valid_moves and uses make_move).
-pub fn execute_move(game: &mut Game, from_square: u8, to_square: u8) -> bool {
+/// `promotion` picks the piece a pawn becomes, when left as None the first matching move is used.
+/// `castling` is the castling rights of the starting position. Returns why the move could not be made if it failed,
+/// the board is left untouched then.
+pub fn execute_move(game: &mut Game, from_square: u8, to_square: u8, promotion: Option<char>, castling: Castling) -> Result<(), String> {
     print!("{:?}", game.player_tracker());
     print!("'s turn.");
-    match game.select_piece(from_square) {
-        Ok(piece) => {
-            println!("You selected: {:?} on square {}", piece, index_to_square(from_square));
-            let moves = valid_moves(from_square, piece, &game.position);
-            if moves.is_empty() {
-                println!("No valid moves for this piece!");
-                return false;
-            }
-            println!("Valid moves:");
-            for (i, m) in moves.iter().enumerate() {
-                println!("{}. {:?}", i, m);
-            }
-            println!("");
-
-            // NOTE, being able to pick move id is important for special moves like promotion so this step should NOT always be left to computer.
-            if let Some(idx) = find_move_to(&moves, to_square) {
-                let chosen_move = moves[idx];
-                // just hnd over game and then let make_mvoe call position?
-                match make_move(chosen_move, game) {
-                    Ok(()) => {
-                        if game.is_over() {
-                            println!("Game has ended: {:?}", game.result);
-                            return true;
-                        }
-                    }
-                    Err(e) => println!("Move failed: {}", e),
-                }
-            } else {
-                println!("No valid move from {:?} to {:?} found.", index_to_square(from_square), index_to_square(to_square));
-            }
-        }
-        Err(msg) => println!("Selection failed: {}", msg),
-    }
-    false
-}
\ No newline at end of file
+    let piece = game.select_piece(from_square).map_err(|msg| format!("Selection failed: {}", msg))?;
+    if piece.color() != game.player_tracker() {
+        return Err(format!("The {:?} on {} can't move, it is {:?}'s turn", piece, index_to_square(from_square), game.player_tracker()));
+    }
+    println!("You selected: {:?} on square {}", piece, index_to_square(from_square));
+    if castles_without_right(game, from_square, to_square, castling) {
+        return Err(format!("Castling from {} to {} isn't allowed in this game", index_to_square(from_square), index_to_square(to_square)));
+    }
+    let moves = valid_moves(from_square, piece, &game.position);
+    if moves.is_empty() {
+        return Err("No valid moves for this piece!".to_string());
+    }
+    println!("Valid moves:");
+    for (i, m) in moves.iter().enumerate() {
+        println!("{}. {:?}", i, m);
+    }
+    println!();
+
+    let Some(chosen_move) = find_move(game, &moves, to_square, promotion) else {
+        return Err(format!("No valid move from {:?} to {:?} found.", index_to_square(from_square), index_to_square(to_square)));
+    };
+    make_move(chosen_move, game).map_err(|e| format!("Move failed: {}", e))?;
+    if game.is_over() {
+        println!("Game has ended: {:?}", game.result);
+    }
+    Ok(())
+}
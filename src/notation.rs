@@ -0,0 +1,176 @@
+// notation.rs reads and writes moves in standard algebraic notation (SAN), e.g. "Nf3", "exd5", "O-O", "e8=Q+"
+
+use chess::*;
+use chess::game::GameResult;
+use chess::piece::{Color as ChessColor, Piece};
+use chess::position::get_piece_at;
+
+use crate::fen::Castling;
+use crate::helper::piece_to_char;
+use crate::move_piece;
+use crate::protocol::MoveRecord;
+
+pub fn square_name(square: u8) -> String { // 0 is a1, 63 is h8
+    format!("{}{}", (b'a' + square % 8) as char, square / 8 + 1)
+}
+
+pub fn square_index(name: &str) -> Option<u8> { // the other way round, None unless it is a file and a rank
+    match name.as_bytes() {
+        &[file @ b'a'..=b'h', rank @ b'1'..=b'8'] => Some((rank - b'1') * 8 + file - b'a'),
+        _ => None,
+    }
+}
+
+/// SAN for `record`, given the game just before (`before`) and just after (`after`) it was played.
+pub fn to_san(before: &Game, after: &Game, record: MoveRecord) -> String {
+    let Some(piece) = get_piece_at(&before.position, record.from) else {
+        return format!("{}{}", square_name(record.from), square_name(record.to)); // shouldn't happen for a move that was played
+    };
+    let letter = piece_to_char(piece);
+    let from_file = record.from % 8;
+    let to_file = record.to % 8;
+    let target_taken = get_piece_at(&before.position, record.to).is_some();
+
+    let mut san = if letter == 'k' && from_file.abs_diff(to_file) == 2 { // the king only ever moves two files when castling
+        if to_file > from_file { "O-O".to_string() } else { "O-O-O".to_string() }
+    }
+    else if letter == 'p' {
+        let mut san = String::new();
+        if from_file != to_file { // pawns only change file when capturing, en passant included
+            san.push((b'a' + from_file) as char);
+            san.push('x');
+        }
+        san.push_str(&square_name(record.to));
+        if let Some(promoted) = get_piece_at(&after.position, record.to) {
+            let promoted = piece_to_char(promoted);
+            if promoted != 'p' {
+                san.push('=');
+                san.push(promoted.to_ascii_uppercase());
+            }
+        }
+        san
+    }
+    else {
+        let mut san = letter.to_ascii_uppercase().to_string();
+        san.push_str(&disambiguation(before, piece, record));
+        if target_taken {
+            san.push('x');
+        }
+        san.push_str(&square_name(record.to));
+        san
+    };
+
+    if matches!(after.result, GameResult::Checkmate(_)) {
+        san.push('#');
+    }
+    else if gives_check(after, piece.color()) {
+        san.push('+');
+    }
+    san
+}
+
+/// The file, rank or both of the starting square, when another piece of the same kind could also reach the target.
+fn disambiguation(before: &Game, piece: Piece, record: MoveRecord) -> String {
+    let rivals: Vec<u8> = (0..64)
+        .filter(|&square| square != record.from)
+        .filter(|&square| match get_piece_at(&before.position, square) {
+            Some(other) => piece_to_char(other) == piece_to_char(piece)
+                && other.color() == piece.color()
+                && valid_moves(square, other, &before.position).iter().any(|m| m.to == record.to),
+            None => false,
+        })
+        .collect();
+    let file = (b'a' + record.from % 8) as char;
+    let rank = (b'1' + record.from / 8) as char;
+    if rivals.is_empty() {
+        String::new()
+    }
+    else if rivals.iter().all(|square| square % 8 != record.from % 8) {
+        file.to_string()
+    }
+    else if rivals.iter().all(|square| square / 8 != record.from / 8) {
+        rank.to_string()
+    }
+    else {
+        format!("{}{}", file, rank)
+    }
+}
+
+/// The move `san` stands for in `game`, together with the game after it. Accepts the usual sloppiness
+/// found in PGN files: 0-0 for castling, promotions without '=', missing or needless check marks and disambiguation.
+pub fn from_san(game: &Game, san: &str, castling: Castling) -> Option<(MoveRecord, Game)> { // castling as in move_piece::castles_without_right
+    let clean: String = san
+        .trim_end_matches("e.p.")
+        .chars()
+        .filter(|c| !matches!(c, '+' | '#' | '!' | '?' | '=' | 'x' | ':' | '-'))
+        .collect();
+    if !clean.is_ascii() { // no move is written with anything else, and the slicing below needs one byte per letter
+        return None;
+    }
+    let clean = clean.replace('0', "O");
+    let castle = match clean.as_str() {
+        "OO" => Some(2i8),
+        "OOO" => Some(-2i8),
+        _ => None,
+    };
+
+    let (body, promotion) = match clean.chars().last() {
+        Some(c) if "QRBN".contains(c) && clean.len() > 2 && castle.is_none() => (&clean[..clean.len() - 1], Some(c.to_ascii_lowercase())),
+        _ => (clean.as_str(), None),
+    };
+    let (letter, rest) = match body.chars().next() {
+        Some(c) if "KQRBN".contains(c) => (c.to_ascii_lowercase(), &body[1..]),
+        _ => ('p', body),
+    };
+    if castle.is_none() && rest.len() < 2 {
+        return None;
+    }
+    let (hint, target) = rest.split_at(rest.len().saturating_sub(2));
+    let target = if castle.is_none() { square_index(target) } else { None };
+
+    let mover = game.player_tracker();
+    let mut found = None;
+    for from in 0..64 {
+        let Some(piece) = get_piece_at(&game.position, from) else { continue };
+        if piece.color() != mover {
+            continue;
+        }
+        for candidate in valid_moves(from, piece, &game.position) {
+            let fits = match castle {
+                Some(step) => piece_to_char(piece) == 'k' && candidate.to as i8 - from as i8 == step,
+                None => piece_to_char(piece) == letter
+                    && Some(candidate.to) == target
+                    && hint.chars().all(|c| square_name(from).contains(c)),
+            };
+            if !fits || move_piece::castles_without_right(game, from, candidate.to, castling) {
+                continue;
+            }
+            let mut after = game.clone();
+            if make_move(candidate, &mut after).is_err() {
+                continue;
+            }
+            let promoted = get_piece_at(&after.position, candidate.to).map(piece_to_char).filter(|&c| c != piece_to_char(piece));
+            if promotion.is_some() && promoted != promotion {
+                continue;
+            }
+            if found.is_some() { // ambiguous
+                return None;
+            }
+            found = Some((MoveRecord { from, to: candidate.to, promotion: promoted }, after));
+        }
+    }
+    found
+}
+
+/// Whether any piece of `mover` attacks the other side's king in `game`.
+pub fn gives_check(game: &Game, mover: ChessColor) -> bool {
+    let king = (0..64).find(|&square| match get_piece_at(&game.position, square) {
+        Some(piece) => piece_to_char(piece) == 'k' && piece.color() != mover,
+        None => false,
+    });
+    let Some(king) = king else { return false };
+    (0..64).any(|square| match get_piece_at(&game.position, square) {
+        Some(piece) if piece.color() == mover => valid_moves(square, piece, &game.position).iter().any(|m| m.to == king),
+        _ => false,
+    })
+}
@@ -0,0 +1,201 @@
+// pgn.rs reads and writes games as PGN (Portable Game Notation) files so they can be archived and opened in other chess programs
+
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::clock::TimeControl;
+use crate::fen::Fen;
+
+pub struct PgnGame {
+    pub tags: Vec<(String, String)>, // in the order they are written, the seven standard tags first
+    pub moves: Vec<String>, // in algebraic notation
+    pub result: String, // "1-0", "0-1", "1/2-1/2" or "*" while the game is still going
+}
+
+impl PgnGame {
+    /// Reads the first game in `text`. Comments, variations and annotations are skipped, only the main line is kept.
+    pub fn parse(text: &str) -> Result<PgnGame, String> {
+        let mut tags = Vec::new();
+        let mut movetext = String::new();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.starts_with('[') {
+                if !movetext.trim().is_empty() { // the tags of the next game
+                    break;
+                }
+                tags.push(parse_tag(line).ok_or_else(|| format!("Bad tag line: {}", line))?);
+            }
+            else if !line.starts_with('%') { // lines starting with % are escaped
+                movetext.push_str(line);
+                movetext.push('\n');
+            }
+        }
+
+        let mut moves = Vec::new();
+        let mut result = "*".to_string();
+        let mut depth = 0; // how deep inside (variations) we are
+        let mut chars = movetext.chars().peekable();
+        let mut token = String::new();
+        while let Some(c) = chars.next() {
+            let ends_token = c.is_whitespace() || matches!(c, '{' | ';' | '(' | ')');
+            if ends_token && !token.is_empty() {
+                if depth == 0 {
+                    read_token(&token, &mut moves, &mut result);
+                }
+                token.clear();
+            }
+            match c {
+                '{' => { // comment until }
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            break;
+                        }
+                    }
+                }
+                ';' => { // comment until the end of the line
+                    for c in chars.by_ref() {
+                        if c == '\n' {
+                            break;
+                        }
+                    }
+                }
+                '(' => depth += 1,
+                ')' => depth = (depth - 1).max(0),
+                c if c.is_whitespace() => {}
+                c => token.push(c),
+            }
+        }
+        if !token.is_empty() && depth == 0 {
+            read_token(&token, &mut moves, &mut result);
+        }
+
+        if tags.is_empty() && moves.is_empty() {
+            return Err("No game found".to_string());
+        }
+        Ok(PgnGame { tags, moves, result })
+    }
+
+    pub fn load(path: &Path) -> Result<PgnGame, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        PgnGame::parse(&text)
+    }
+
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags.iter().find(|(tag, _)| tag == name).map(|(_, value)| value.as_str())
+    }
+
+    /// Half moves played before the first move of the game, taken from the FEN tag of set up positions.
+    pub fn start_ply(&self) -> usize {
+        self.tag("FEN").and_then(|fen| Fen::parse(fen).ok()).map_or(0, |fen| fen.start_ply())
+    }
+
+    pub fn to_pgn(&self) -> String {
+        let mut pgn = String::new();
+        for (name, value) in &self.tags {
+            pgn.push_str(&format!("[{} \"{}\"]\n", name, value.replace('\\', "\\\\").replace('"', "\\\"")));
+        }
+        pgn.push('\n');
+
+        let start = self.start_ply();
+        let mut tokens = Vec::new();
+        for (i, san) in self.moves.iter().enumerate() {
+            let ply = start + i;
+            if ply.is_multiple_of(2) {
+                tokens.push(format!("{}.", ply / 2 + 1));
+            }
+            else if i == 0 { // set up with black to move
+                tokens.push(format!("{}...", ply / 2 + 1));
+            }
+            tokens.push(san.clone());
+        }
+        tokens.push(self.result.clone());
+
+        let mut line = String::new(); // the standard asks for lines shorter than 80 characters
+        for token in tokens {
+            if !line.is_empty() && line.len() + 1 + token.len() > 79 {
+                pgn.push_str(&line);
+                pgn.push('\n');
+                line.clear();
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(&token);
+        }
+        pgn.push_str(&line);
+        pgn.push('\n');
+        pgn
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_pgn())
+    }
+}
+
+fn parse_tag(line: &str) -> Option<(String, String)> { // [Name "value"]
+    let inner = line.strip_prefix('[')?.trim_end().strip_suffix(']')?;
+    let (name, value) = inner.split_once(char::is_whitespace)?;
+    let value = value.trim().strip_prefix('"')?.strip_suffix('"')?;
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            unescaped.extend(chars.next());
+        }
+        else {
+            unescaped.push(c);
+        }
+    }
+    Some((name.to_string(), unescaped))
+}
+
+fn read_token(token: &str, moves: &mut Vec<String>, result: &mut String) { // sorts a movetext token into a move, a result or noise
+    if matches!(token, "1-0" | "0-1" | "1/2-1/2" | "*") {
+        *result = token.to_string();
+        return;
+    }
+    if token.starts_with('$') { // numeric annotation glyph
+        return;
+    }
+    let san = match token.find('.') { // "12." and "12...e5" carry a move number
+        Some(dot) if token[..dot].chars().all(|c| c.is_ascii_digit()) => token[dot..].trim_start_matches('.'),
+        _ => token,
+    };
+    if san.is_empty() || san.chars().all(|c| c.is_ascii_digit()) {
+        return;
+    }
+    moves.push(san.to_string());
+}
+
+/// The current UTC date and time as (year, month, day, hour, minute, second).
+pub fn now() -> (i64, u32, u32, u32, u32, u32) {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    let (days, rest) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // days since 1970-01-01 to a civil date, from Howard Hinnant's date algorithms
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day, (rest / 3600) as u32, (rest % 3600 / 60) as u32, (rest % 60) as u32)
+}
+
+pub fn time_control_tag(time_control: Option<TimeControl>) -> String { // PGN counts in seconds, "300+3" for five minutes plus three seconds
+    match time_control {
+        Some(tc) => format!("{}+{}", tc.base_ms / 1000, tc.increment_ms / 1000),
+        None => "-".to_string(),
+    }
+}
+
+pub fn file_safe(name: &str) -> String { // keeps player names from turning into odd file names
+    let safe: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    if safe.is_empty() { "Player".to_string() } else { safe }
+}
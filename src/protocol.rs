@@ -0,0 +1,155 @@
+// protocol.rs describes the messages sent between the two players, one JSON object per line
+
+use serde::{Deserialize, Serialize};
+
+use chess::piece::Color as ChessColor;
+
+use crate::clock::TimeControl;
+
+pub const PROTOCOL_VERSION: u32 = 3; // bump when the message format changes in a way older builds can't read
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    White,
+    Black,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorChoice { // the color a player asks for before the game starts
+    White,
+    Black,
+    Random,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct MoveRecord { // a move as it was played, enough to replay it on a fresh board
+    pub from: u8,
+    pub to: u8,
+    pub promotion: Option<char>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GameView { // the host's game the way a spectator sees it
+    pub white: String,
+    pub black: String,
+    pub time_control: Option<TimeControl>,
+    pub fen: Option<String>, // None is the normal start
+    pub moves: Vec<MoveRecord>,
+    pub result: Option<String>, // how the game ended, named like the win messages
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    Hello { name: String, color: ColorChoice, time_control: Option<TimeControl>, #[serde(default)] spectator: bool, #[serde(default)] session: Option<u64> }, // first thing both sides send, a spectator only watches, session is what a returning client got in its last Setup
+    Setup { color: Side, time_control: Option<TimeControl>, #[serde(default)] fen: Option<String>, #[serde(default)] session: Option<u64> }, // host's answer to the hello, tells the client which color it plays, on what clock and from what position (None is the normal start), and the session that gets its seat back after a drop
+    GameFull, // host's answer to a second player while the game already has one
+    Spectate { view: GameView, clocks: Option<(u64, u64)> }, // the host's answer to a spectator's hello and to every change after it, clocks are white's and black's ms
+    StartPosition { fen: Option<String> }, // the host set up a new starting position before the first move
+    Move { from: u8, to: u8, promotion: Option<char>, hash: u64, clock: Option<u64> }, // promotion is 'q', 'r', 'b' or 'n' when a pawn promotes, hash is the position after the move, clock the mover's remaining ms
+    IllegalMove { from: u8, to: u8, reason: String }, // reply to a move we refused to play
+    ResyncRequest, // client asks the host for its moves after the boards drifted apart
+    Resync { moves: Vec<MoveRecord> }, // every move of the game according to the host
+    Resume { moves: Vec<MoveRecord> }, // sent by both sides after (re)connecting, so a dropped game picks up where it stopped
+    Resign,
+    Timeout { color: Side }, // that side's clock ran out
+    DrawOffer,
+    DrawAccept,
+    DrawDecline,
+    RematchOffer, // only after a game has ended, colors swap if accepted
+    RematchAccept,
+    RematchDecline,
+    CreateGame { game: String }, // to a relay: open a game under that name and wait for a second player, who joins it as the client
+    JoinGame { game: String }, // to a relay: play the game of that name
+    ListGames, // to a relay: which games are waiting for a second player
+    Games { games: Vec<String> }, // the relay's answer to ListGames
+    Paired { host: bool }, // from the relay once both players are there, everything after it goes straight to the other player
+    RelayError { reason: String }, // the relay turned down a CreateGame or JoinGame
+    Chat { text: String, #[serde(default)] from: Option<String> }, // from is filled in by the host when passing a player's message on to spectators
+    Ping,
+    Pong,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope { // every line carries the protocol version next to the message itself
+    v: u32,
+    #[serde(flatten)]
+    message: Message,
+}
+
+pub enum Incoming { // what the connection thread hands over to the gui
+    Connected, // the handshake went through
+    Message(Message),
+    Malformed(String),
+}
+
+impl Side {
+    pub fn opposite(self) -> Side {
+        match self {
+            Side::White => Side::Black,
+            Side::Black => Side::White,
+        }
+    }
+
+    pub fn from_chess(color: ChessColor) -> Side {
+        match color {
+            ChessColor::White => Side::White,
+            ChessColor::Black => Side::Black,
+        }
+    }
+
+    pub fn to_chess(self) -> ChessColor {
+        match self {
+            Side::White => ChessColor::White,
+            Side::Black => ChessColor::Black,
+        }
+    }
+}
+
+impl ColorChoice {
+    pub fn parse(text: &str) -> Option<ColorChoice> {
+        match text.to_lowercase().as_str() {
+            "white" | "w" => Some(ColorChoice::White),
+            "black" | "b" => Some(ColorChoice::Black),
+            "random" | "r" => Some(ColorChoice::Random),
+            _ => None,
+        }
+    }
+}
+
+/// Decide which side the host plays. The host's wish wins when both ask for the same color, `coin` settles it when neither cares.
+pub fn resolve_colors(host: ColorChoice, client: ColorChoice, coin: bool) -> Side {
+    match (host, client) {
+        (ColorChoice::White, _) => Side::White,
+        (ColorChoice::Black, _) => Side::Black,
+        (ColorChoice::Random, ColorChoice::White) => Side::Black,
+        (ColorChoice::Random, ColorChoice::Black) => Side::White,
+        (ColorChoice::Random, ColorChoice::Random) => if coin { Side::White } else { Side::Black },
+    }
+}
+
+/// Read only the protocol version of a line, so a handshake can tell "incompatible build" apart from garbage.
+pub fn peek_version(line: &str) -> Option<u32> {
+    let value: serde_json::Value = serde_json::from_str(line).ok()?;
+    value.get("v")?.as_u64().map(|v| v as u32)
+}
+
+/// Turn a message into a single line of JSON (without the newline).
+pub fn encode(message: &Message) -> String {
+    let envelope = Envelope { v: PROTOCOL_VERSION, message: message.clone() };
+    serde_json::to_string(&envelope).expect("messages always serialize")
+}
+
+/// Parse a line received from the second player, describing what was wrong if it isn't a message we understand.
+pub fn decode(line: &str) -> Result<Message, String> {
+    let value: serde_json::Value = serde_json::from_str(line).map_err(|e| format!("not valid JSON ({})", e))?;
+    match value.get("v").and_then(|v| v.as_u64()) {
+        Some(v) if v == PROTOCOL_VERSION as u64 => {}
+        Some(v) => return Err(format!("unsupported protocol version {} (we speak {})", v, PROTOCOL_VERSION)),
+        None => return Err("missing protocol version".to_string()),
+    }
+    let envelope: Envelope = serde_json::from_value(value).map_err(|e| format!("unknown or malformed message ({})", e))?;
+    Ok(envelope.message)
+}
@@ -0,0 +1,62 @@
+// replay.rs loads a PGN file and keeps every position of the game so it can be stepped through in the gui
+
+use std::path::Path;
+
+use chess::*;
+
+use crate::fen::{self, Castling, Fen};
+use crate::notation;
+use crate::pgn::PgnGame;
+use crate::protocol::MoveRecord;
+
+pub struct Replay {
+    pub pgn: PgnGame,
+    pub positions: Vec<Game>, // positions[0] is the start, positions[i] the board after i moves
+    pub records: Vec<MoveRecord>,
+    pub san: Vec<String>, // written by us, so it looks the same as in a live game
+    pub ply: usize, // how many moves are on the board right now
+    pub start_ply: usize, // half moves before the first one, for games set up from a FEN
+    pub castling: Castling, // what the starting position allows
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> Result<Replay, String> {
+        let pgn = PgnGame::load(path)?;
+        if let Some(text) = pgn.tag("FEN") {
+            Fen::parse(text).map_err(|e| format!("Bad FEN tag: {}", e))?;
+        }
+        let mut game = fen::start_game(pgn.tag("FEN"));
+        let start_ply = pgn.start_ply();
+        let castling = fen::castling_rights(pgn.tag("FEN"));
+        let mut positions = vec![game.clone()];
+        let mut records = Vec::new();
+        let mut san = Vec::new();
+        for (i, text) in pgn.moves.iter().enumerate() {
+            let Some((record, after)) = notation::from_san(&game, text, castling) else {
+                return Err(format!("Move {} ({}) is not legal here", (start_ply + i) / 2 + 1, text));
+            };
+            san.push(notation::to_san(&game, &after, record));
+            records.push(record);
+            positions.push(after.clone());
+            game = after;
+        }
+        Ok(Replay { pgn, positions, records, san, ply: 0, start_ply, castling })
+    }
+
+    pub fn go_to(&mut self, ply: usize) {
+        self.ply = ply.min(self.records.len());
+    }
+
+    pub fn step(&mut self, forward: bool) {
+        if forward {
+            self.go_to(self.ply + 1);
+        }
+        else {
+            self.go_to(self.ply.saturating_sub(1));
+        }
+    }
+
+    pub fn game(&self) -> &Game {
+        &self.positions[self.ply]
+    }
+}
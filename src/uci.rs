@@ -0,0 +1,220 @@
+// uci.rs runs an external chess engine (anything speaking UCI, e.g. stockfish) as a subprocess
+// and translates between our square indices and UCI's long algebraic moves ("e2e4", "e7e8q")
+
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam::channel::{self, Receiver};
+
+use crate::analysis::{Analysis, Score};
+use crate::notation::{square_index, square_name};
+use crate::protocol::MoveRecord;
+use crate::worker::Worker;
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10); // for everything except the search itself
+
+#[derive(Clone, Copy, Debug)]
+pub enum Limit { // how long the engine may think about a move
+    MoveTime(u64), // milliseconds
+    Depth(u32), // half moves
+}
+
+pub struct UciEngine {
+    child: Child,
+    stdin: ChildStdin,
+    lines: Receiver<String>, // the engine's output, one line at a time
+    pub name: String,
+}
+
+impl UciEngine {
+    /// Starts the engine at `path` and waits until it is ready for a position.
+    pub fn start(path: &str) -> Result<UciEngine, String> {
+        let mut child = Command::new(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("could not start engine '{}': {}", path, e))?;
+        let stdin = child.stdin.take().ok_or("engine has no stdin")?;
+        let stdout = child.stdout.take().ok_or("engine has no stdout")?;
+        let (tx, lines) = channel::unbounded();
+        thread::spawn(move || { // stops when the engine closes its output
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut engine = UciEngine { child, stdin, lines, name: path.to_string() };
+        engine.send("uci")?;
+        loop {
+            let line = engine.read_line(REPLY_TIMEOUT)?;
+            if let Some(name) = line.strip_prefix("id name ") {
+                engine.name = name.trim().to_string();
+            }
+            if line.trim() == "uciok" {
+                break;
+            }
+        }
+        engine.send("isready")?;
+        engine.wait_for("readyok")?;
+        Ok(engine)
+    }
+
+    fn send(&mut self, command: &str) -> Result<(), String> {
+        writeln!(self.stdin, "{}", command)
+            .and_then(|_| self.stdin.flush())
+            .map_err(|e| format!("could not talk to the engine: {}", e))
+    }
+
+    fn read_line(&self, timeout: Duration) -> Result<String, String> {
+        self.lines.recv_timeout(timeout).map_err(|_| "the engine stopped answering".to_string())
+    }
+
+    fn wait_for(&self, expected: &str) -> Result<(), String> {
+        while self.read_line(REPLY_TIMEOUT)?.trim() != expected {}
+        Ok(())
+    }
+
+    /// The engine's move for the game that started at `start_fen` (None is the normal start) and went through `moves`.
+    /// None when it has no move, i.e. the game is over.
+    pub fn best_move(&mut self, start_fen: Option<&str>, moves: &[MoveRecord], limit: Limit) -> Result<Option<MoveRecord>, String> {
+        Ok(self.search(start_fen, moves, limit, |_| true)?.best)
+    }
+
+    /// Like best_move, but also keeps the score. `on_info` hears about every "info" line with a score while the engine thinks,
+    /// returning false tells the engine to stop and answer with what it has.
+    pub fn search(&mut self, start_fen: Option<&str>, moves: &[MoveRecord], limit: Limit, mut on_info: impl FnMut(Analysis) -> bool) -> Result<Analysis, String> {
+        let mut position = match start_fen {
+            Some(fen) => format!("position fen {}", fen),
+            None => "position startpos".to_string(),
+        };
+        if !moves.is_empty() {
+            position.push_str(" moves");
+            for record in moves {
+                position.push(' ');
+                position.push_str(&to_uci(*record));
+            }
+        }
+        self.send(&position)?;
+        self.send(&match limit {
+            Limit::MoveTime(ms) => format!("go movetime {}", ms),
+            Limit::Depth(depth) => format!("go depth {}", depth),
+        })?;
+        let patience = match limit { // depth searches can take a while, but not forever
+            Limit::MoveTime(ms) => Duration::from_millis(ms) + REPLY_TIMEOUT,
+            Limit::Depth(_) => REPLY_TIMEOUT * 6,
+        };
+        let mut latest = Analysis { best: None, score: None, depth: 0 };
+        let mut stopped = false;
+        loop {
+            let line = self.read_line(patience)?;
+            if let Some(info) = parse_info(&line) {
+                latest = info;
+                if !on_info(info) && !stopped {
+                    self.send("stop")?; // it still sends its bestmove, which keeps us in step for the next search
+                    stopped = true;
+                }
+            }
+            if let Some(rest) = line.strip_prefix("bestmove") {
+                latest.best = match rest.split_whitespace().next().unwrap_or("(none)") {
+                    "(none)" | "0000" => None,
+                    text => Some(from_uci(text).ok_or_else(|| format!("the engine sent a move we can't read: {}", text))?),
+                };
+                return Ok(latest);
+            }
+        }
+    }
+}
+
+impl Drop for UciEngine {
+    fn drop(&mut self) {
+        let _ = self.send("quit");
+        thread::sleep(Duration::from_millis(50)); // a moment to quit on its own before we insist
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Asks the external engine for its move from a thread of its own, so a slow or stuck engine never freezes the gui.
+/// The bestmove arrives on the returned channel, None when the engine has no move or fails, failures are printed.
+pub fn think(engine: Arc<Mutex<UciEngine>>, start_fen: Option<String>, moves: Vec<MoveRecord>, limit: Limit) -> Receiver<Option<MoveRecord>> {
+    let (tx, rx) = channel::bounded(1);
+    thread::spawn(move || {
+        let answer = engine.lock().unwrap().best_move(start_fen.as_deref(), &moves, limit).unwrap_or_else(|e| {
+            println!("Engine error: {}", e);
+            None
+        });
+        let _ = tx.send(answer);
+    });
+    rx
+}
+
+/// Runs search on a background thread, every score the engine reports arrives on the returned Worker, the final answer last.
+/// Dropping the Worker tells the engine to stop at its next report.
+pub fn analyse(engine: Arc<Mutex<UciEngine>>, start_fen: Option<String>, moves: Vec<MoveRecord>, limit: Limit) -> Worker<Analysis> {
+    Worker::start(move |tx, stop| {
+        if stop.load(Ordering::Relaxed) { // the position changed while an earlier search had the engine
+            return;
+        }
+        let updates = tx.clone();
+        match engine.lock().unwrap().search(start_fen.as_deref(), &moves, limit, |info| !stop.load(Ordering::Relaxed) && updates.send(info).is_ok()) {
+            Ok(analysis) => { let _ = tx.send(analysis); }
+            Err(e) => println!("Engine error: {}", e),
+        }
+    })
+}
+
+/// The depth, score and first move of the main line in an "info" line, if it has a score.
+pub fn parse_info(line: &str) -> Option<Analysis> {
+    let mut words = line.split_whitespace();
+    if words.next() != Some("info") {
+        return None;
+    }
+    let mut analysis = Analysis { best: None, score: None, depth: 0 };
+    while let Some(word) = words.next() {
+        match word {
+            "depth" => analysis.depth = words.next()?.parse().ok()?,
+            "score" => {
+                let kind = words.next()?;
+                let value: i32 = words.next()?.parse().ok()?;
+                analysis.score = match kind {
+                    "cp" => Some(Score::Centipawns(value)),
+                    "mate" => Some(Score::Mate(value)),
+                    _ => None,
+                };
+            }
+            "pv" => {
+                analysis.best = words.next().and_then(from_uci);
+                break; // the rest of the line is the main line
+            }
+            "string" => break, // free text from here on
+            _ => {}
+        }
+    }
+    analysis.score.map(|_| analysis)
+}
+
+pub fn to_uci(record: MoveRecord) -> String {
+    let mut text = format!("{}{}", square_name(record.from), square_name(record.to));
+    text.extend(record.promotion);
+    text
+}
+
+pub fn from_uci(text: &str) -> Option<MoveRecord> {
+    if !text.is_ascii() || !(4..=5).contains(&text.len()) {
+        return None;
+    }
+    let promotion = match text[4..].chars().next() {
+        Some(c) if "qrbn".contains(c) => Some(c),
+        Some(_) => return None,
+        None => None,
+    };
+    Some(MoveRecord { from: square_index(&text[0..2])?, to: square_index(&text[2..4])?, promotion })
+}
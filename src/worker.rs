@@ -0,0 +1,31 @@
+// worker.rs runs a job on its own thread that hands back what it finds and stops once nobody wants it anymore
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use crossbeam::channel::{self, Receiver, Sender};
+
+/// A job running on its own thread. What it finds arrives on `rx`, dropping the Worker tells the job to stop.
+pub struct Worker<T> {
+    pub rx: Receiver<T>,
+    stop: Arc<AtomicBool>,
+}
+
+impl<T: Send + 'static> Worker<T> {
+    /// Runs `job` on a new thread with where to send its findings and a flag that turns true once the Worker is dropped.
+    /// The job should look at the flag whenever it can, a send only fails after the drop if the job tries one.
+    pub fn start(job: impl FnOnce(Sender<T>, Arc<AtomicBool>) + Send + 'static) -> Worker<T> {
+        let (tx, rx) = channel::unbounded();
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&stop);
+        thread::spawn(move || job(tx, flag));
+        Worker { rx, stop }
+    }
+}
+
+impl<T> Drop for Worker<T> {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,56 @@
+// tests for finding games on the local network, a host announces on loopback and a browser listens for it
+
+#![allow(dead_code)] // only announcing and listening are used
+
+#[path = "../src/clock.rs"]
+mod clock;
+#[path = "../src/connection_state.rs"]
+mod connection_state;
+#[path = "../src/discovery.rs"]
+mod discovery;
+#[path = "../src/protocol.rs"]
+mod protocol;
+#[path = "../src/worker.rs"]
+mod worker;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use connection_state::ConnectionState;
+use discovery::Announcement;
+use protocol::PROTOCOL_VERSION;
+
+#[test]
+fn an_announcement_is_a_small_json_object() {
+    let announcement = Announcement { v: PROTOCOL_VERSION, name: "Ada".to_string(), port: 7878, time_control: None, playing: true };
+    let datagram = serde_json::to_vec(&announcement).unwrap();
+    assert!(datagram.len() < 1024, "a listener reads announcements into 1024 bytes");
+    let fields: serde_json::Value = serde_json::from_slice(&datagram).unwrap();
+    assert_eq!(fields["v"], PROTOCOL_VERSION);
+    assert_eq!(fields["name"], "Ada");
+    assert_eq!(fields["port"], 7878);
+    assert_eq!(fields["playing"], true);
+    assert_eq!(serde_json::from_slice::<Announcement>(&datagram).unwrap(), announcement);
+}
+
+#[test]
+fn a_browser_hears_an_announced_game_and_lets_go_of_the_port() { // the only test binding the discovery port
+    let browser = discovery::listen().expect("the discovery port should be free");
+    let state = Arc::new(Mutex::new(ConnectionState::new()));
+    state.lock().unwrap().name = "loopback test".to_string();
+    thread::spawn(move || discovery::announce(40123, state));
+
+    let host = loop { // other hosts on the network may be announcing too
+        let host = browser.rx.recv_timeout(Duration::from_secs(5)).expect("the announcement should arrive");
+        if host.announcement.name == "loopback test" {
+            break host;
+        }
+    };
+    assert_eq!(host.address.port(), 40123);
+    assert!(!host.announcement.playing);
+
+    drop(browser);
+    thread::sleep(Duration::from_secs(1)); // the listener notices within a quarter of a second
+    assert!(discovery::listen().is_ok(), "the port should be free once nobody is browsing");
+}
@@ -0,0 +1,14 @@
+#!/bin/sh
+# a scripted stand-in for a UCI engine: answers 1. e4 with e5, promotes on e8 when asked from the test position,
+# and plays e2e4 from anything else
+while read -r line; do
+    case "$line" in
+        uci) echo "id name Fake Engine"; echo "id author mblomst"; echo "uciok" ;;
+        isready) echo "readyok" ;;
+        "position startpos moves e2e4") reply="e7e5" ;;
+        "position fen 8/4P3/8/8/8/8/k7/7K w - - 0 1") reply="e7e8q" ;;
+        position*) reply="e2e4" ;;
+        go*) echo "info depth 1 score cp 20 pv $reply"; echo "bestmove $reply" ;;
+        quit) exit 0 ;;
+    esac
+done
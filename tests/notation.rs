@@ -0,0 +1,26 @@
+// tests for reading and writing algebraic notation
+
+#![allow(dead_code)] // only from_san is used
+
+#[path = "../src/clock.rs"]
+mod clock;
+#[path = "../src/fen.rs"]
+mod fen;
+#[path = "../src/helper.rs"]
+mod helper;
+#[path = "../src/move_piece.rs"]
+mod move_piece;
+#[path = "../src/notation.rs"]
+mod notation;
+#[path = "../src/protocol.rs"]
+mod protocol;
+
+use chess::*;
+
+#[test]
+fn from_san_refuses_moves_with_non_ascii_marks_instead_of_panicking() {
+    let game = Game::new(initialize_board());
+    for san in ["e4‼", "Nf3⁉", "é4", "O‑O", "e8=♕"] { // the kind of thing PGN files from other programs contain
+        assert!(notation::from_san(&game, san, fen::Castling::ALL).is_none(), "{:?} should not parse", san);
+    }
+}
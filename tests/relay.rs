@@ -0,0 +1,139 @@
+// tests for the mblomst-relay binary, run on loopback with raw protocol lines standing in for the players
+
+#![allow(dead_code)] // only the message format is used
+
+#[path = "../src/clock.rs"]
+mod clock;
+#[path = "../src/protocol.rs"]
+mod protocol;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use protocol::Message;
+
+struct Relay { // the relay process, killed when the test ends
+    child: Child,
+    output: BufReader<ChildStdout>, // kept open, the relay logs to it
+    port: u16,
+}
+
+impl Relay {
+    fn start() -> Relay {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_mblomst-relay"))
+            .arg("0")
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("relay should start");
+        let mut output = BufReader::new(child.stdout.take().unwrap());
+        let mut line = String::new();
+        output.read_line(&mut line).unwrap();
+        let port = line.trim().rsplit(' ').next().and_then(|port| port.parse().ok()).expect("relay should print its port");
+        Relay { child, output, port }
+    }
+
+    fn connect(&self) -> Player {
+        let stream = TcpStream::connect(("127.0.0.1", self.port)).expect("relay should accept");
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        Player { reader: BufReader::new(stream.try_clone().unwrap()), stream }
+    }
+
+    fn games(&self) -> Vec<String> {
+        let mut player = self.connect();
+        player.send(&Message::ListGames);
+        match player.receive() {
+            Message::Games { games } => games,
+            other => panic!("expected the list of games, got {:?}", other),
+        }
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+struct Player {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Player {
+    fn send(&mut self, message: &Message) {
+        writeln!(self.stream, "{}", protocol::encode(message)).unwrap();
+    }
+
+    fn receive(&mut self) -> Message {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).expect("relay should answer");
+        protocol::decode(line.trim()).expect("relay should send protocol messages")
+    }
+}
+
+fn wait_for_lobby() { // creating a game happens on the relay's own thread
+    thread::sleep(Duration::from_millis(200));
+}
+
+#[test]
+fn pairs_a_created_game_with_its_joiner_and_relays_both_ways() {
+    let relay = Relay::start();
+    let mut creator = relay.connect();
+    creator.send(&Message::CreateGame { game: "friday".to_string() });
+    wait_for_lobby();
+    assert_eq!(relay.games(), vec!["friday".to_string()]);
+
+    let mut joiner = relay.connect();
+    joiner.send(&Message::JoinGame { game: "friday".to_string() });
+    assert_eq!(joiner.receive(), Message::Paired { host: false });
+    assert_eq!(creator.receive(), Message::Paired { host: true });
+    assert!(relay.games().is_empty(), "a full game is no longer listed");
+
+    creator.send(&Message::Chat { text: "hi".to_string(), from: None });
+    assert_eq!(joiner.receive(), Message::Chat { text: "hi".to_string(), from: None });
+    joiner.send(&Message::DrawOffer);
+    assert_eq!(creator.receive(), Message::DrawOffer);
+}
+
+#[test]
+fn refuses_joining_a_missing_game_and_creating_a_taken_name() {
+    let relay = Relay::start();
+    let mut joiner = relay.connect();
+    joiner.send(&Message::JoinGame { game: "nobody".to_string() });
+    assert!(matches!(joiner.receive(), Message::RelayError { .. }));
+
+    let mut creator = relay.connect();
+    creator.send(&Message::CreateGame { game: "taken".to_string() });
+    wait_for_lobby();
+    let mut second = relay.connect();
+    second.send(&Message::CreateGame { game: "taken".to_string() });
+    assert!(matches!(second.receive(), Message::RelayError { .. }));
+    assert_eq!(relay.games(), vec!["taken".to_string()]);
+}
+
+#[test]
+fn forgets_a_game_whose_creator_left() {
+    let relay = Relay::start();
+    let mut creator = relay.connect();
+    creator.send(&Message::CreateGame { game: "gone".to_string() });
+    wait_for_lobby();
+    drop(creator);
+    thread::sleep(Duration::from_millis(1500)); // the relay checks on waiting creators every second
+    assert!(relay.games().is_empty());
+
+    let mut joiner = relay.connect();
+    joiner.send(&Message::JoinGame { game: "gone".to_string() });
+    assert!(matches!(joiner.receive(), Message::RelayError { .. }));
+}
+
+#[test]
+fn answers_an_endless_first_line_instead_of_reading_it_all() {
+    let relay = Relay::start();
+    let mut stranger = relay.connect();
+    stranger.stream.write_all(&[b'x'; 10_000]).unwrap(); // no newline, the relay stops reading well before the end
+    assert!(matches!(stranger.receive(), Message::RelayError { .. }));
+}
@@ -0,0 +1,108 @@
+// tests for the UCI engine wrapper, run against tests/fake_uci_engine.sh instead of a real engine
+
+#![allow(dead_code)] // only the parts uci.rs needs are used
+
+#[path = "../src/analysis.rs"]
+mod analysis;
+#[path = "../src/clock.rs"]
+mod clock;
+#[path = "../src/fen.rs"]
+mod fen;
+#[path = "../src/helper.rs"]
+mod helper;
+#[path = "../src/move_piece.rs"]
+mod move_piece;
+#[path = "../src/notation.rs"]
+mod notation;
+#[path = "../src/protocol.rs"]
+mod protocol;
+#[path = "../src/uci.rs"]
+mod uci;
+#[path = "../src/worker.rs"]
+mod worker;
+
+use analysis::Score;
+use protocol::MoveRecord;
+use uci::{Limit, UciEngine};
+
+fn fake_engine() -> UciEngine {
+    UciEngine::start(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fake_uci_engine.sh")).expect("fake engine should start")
+}
+
+#[test]
+fn moves_round_trip_through_uci_notation() {
+    let e2e4 = MoveRecord { from: 12, to: 28, promotion: None };
+    assert_eq!(uci::to_uci(e2e4), "e2e4");
+    assert_eq!(uci::from_uci("e2e4"), Some(e2e4));
+
+    let promotion = MoveRecord { from: 52, to: 60, promotion: Some('n') };
+    assert_eq!(uci::to_uci(promotion), "e7e8n");
+    assert_eq!(uci::from_uci("e7e8n"), Some(promotion));
+    assert_eq!(uci::from_uci("a1h8"), Some(MoveRecord { from: 0, to: 63, promotion: None }));
+}
+
+#[test]
+fn bad_uci_moves_are_refused() {
+    for text in ["", "e2", "e2e9", "i2e4", "e0e4", "E2E4", "e7e8k", "e2e4qq", "é2e4"] {
+        assert_eq!(uci::from_uci(text), None, "{:?} should not parse", text);
+    }
+}
+
+#[test]
+fn engine_handshake_reads_its_name() {
+    assert_eq!(fake_engine().name, "Fake Engine");
+}
+
+#[test]
+fn engine_answers_the_moves_played_so_far() {
+    let mut engine = fake_engine();
+    let played = [MoveRecord { from: 12, to: 28, promotion: None }];
+    let reply = engine.best_move(None, &played, Limit::MoveTime(50)).unwrap();
+    assert_eq!(reply, Some(MoveRecord { from: 52, to: 36, promotion: None }));
+
+    let opening = engine.best_move(None, &[], Limit::Depth(3)).unwrap(); // the same engine keeps answering
+    assert_eq!(opening, Some(MoveRecord { from: 12, to: 28, promotion: None }));
+}
+
+#[test]
+fn engine_promotes_from_a_fen_position() {
+    let mut engine = fake_engine();
+    let reply = engine.best_move(Some("8/4P3/8/8/8/8/k7/7K w - - 0 1"), &[], Limit::Depth(1)).unwrap();
+    assert_eq!(reply, Some(MoveRecord { from: 52, to: 60, promotion: Some('q') }));
+}
+
+#[test]
+fn info_lines_give_the_score_and_main_line() {
+    let info = uci::parse_info("info depth 12 seldepth 18 multipv 1 score cp -35 nodes 1234 pv g8f6 c2c4 e7e6").unwrap();
+    assert_eq!(info.depth, 12);
+    assert_eq!(info.score, Some(Score::Centipawns(-35)));
+    assert_eq!(info.best, Some(MoveRecord { from: 62, to: 45, promotion: None }));
+
+    let mate = uci::parse_info("info depth 5 score mate -2 pv e1e2").unwrap();
+    assert_eq!(mate.score, Some(Score::Mate(-2)));
+    assert!(uci::parse_info("info string NNUE evaluation enabled").is_none());
+    assert!(uci::parse_info("bestmove e2e4").is_none());
+}
+
+#[test]
+fn search_keeps_the_engine_score() {
+    let mut engine = fake_engine();
+    let mut heard = Vec::new();
+    let analysis = engine.search(None, &[], Limit::Depth(1), |info| { heard.push(info); true }).unwrap();
+    assert_eq!(analysis.best, Some(MoveRecord { from: 12, to: 28, promotion: None }));
+    assert_eq!(analysis.score, Some(Score::Centipawns(20)));
+    assert_eq!(heard.len(), 1);
+}
+
+#[test]
+fn think_runs_in_the_background() {
+    let engine = std::sync::Arc::new(std::sync::Mutex::new(fake_engine()));
+    let answer = uci::think(engine, None, Vec::new(), Limit::MoveTime(10));
+    let reply = answer.recv_timeout(std::time::Duration::from_secs(10)).expect("an answer");
+    assert_eq!(reply, Some(MoveRecord { from: 12, to: 28, promotion: None }));
+}
+
+#[test]
+fn missing_engine_is_an_error() {
+    assert!(UciEngine::start("/no/such/engine").is_err());
+}